@@ -0,0 +1,58 @@
+// Compares running the examples with and without superinstructions:
+//
+//     cargo bench --bench superinstructions
+
+use std::fs;
+use std::time::{Duration, Instant};
+
+use glacier2::{Glacier, OutputSink, VMOptions};
+
+const EXAMPLES: [&str; 2] = ["eratosthenes", "mandelbrot"];
+const SAMPLES: usize = 15;
+const ITERATIONS: u32 = 20;
+
+// Average time of one run of the compiled example over many runs, without compiling it
+// or printing its output
+fn sample(source: &str, superinstructions: bool) -> Duration {
+    let options = VMOptions::default().superinstructions(superinstructions);
+    let mut glacier = Glacier::with_options(options);
+    glacier.set_output(OutputSink::callback(|_| {}));
+    glacier.compile(source).unwrap();
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        glacier.run().unwrap();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn median(mut times: Vec<Duration>) -> Duration {
+    times.sort();
+    times[times.len() / 2]
+}
+
+fn main() {
+    println!(
+        "{:<14} {:>10} {:>10} {:>8}",
+        "example", "plain", "fused", "speedup"
+    );
+    for name in EXAMPLES {
+        let path = format!("{}/examples/{name}.glc", env!("CARGO_MANIFEST_DIR"));
+        let source = fs::read_to_string(path).unwrap();
+
+        // Alternated, so that both see the same changes in machine load
+        let (mut plain, mut fused) = (Vec::new(), Vec::new());
+        for _ in 0..SAMPLES {
+            plain.push(sample(&source, false));
+            fused.push(sample(&source, true));
+        }
+
+        let (plain, fused) = (median(plain), median(fused));
+        println!(
+            "{name:<14} {:>10.2?} {:>10.2?} {:>7.2}x",
+            plain,
+            fused,
+            plain.as_secs_f64() / fused.as_secs_f64()
+        );
+    }
+}
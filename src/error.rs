@@ -0,0 +1,118 @@
+use std::fmt;
+
+use crate::ast::AstSpan;
+use crate::parser::Rule;
+
+// Where an error happened and what went wrong
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorInfo {
+    // Byte offsets into the source the error was reported against
+    pub span: AstSpan,
+    // 1-based, column counts chars rather than bytes
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GlacierError {
+    Syntax(ErrorInfo),
+    Compile(ErrorInfo),
+    Runtime(ErrorInfo),
+    // Execution stopped by VMOptions::max_instructions or the interrupt flag,
+    // pointing at the instruction that was about to run
+    BudgetExhausted(ErrorInfo),
+    Interrupted(ErrorInfo),
+}
+
+impl ErrorInfo {
+    pub fn new(source: &str, span: AstSpan, message: String) -> Self {
+        let start = span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        ErrorInfo {
+            span,
+            line: source[..start].matches('\n').count() + 1,
+            column: source[line_start..start].chars().count() + 1,
+            message,
+        }
+    }
+}
+
+impl GlacierError {
+    // `error` comes from parsing the part of `source` starting at `offset`
+    pub fn syntax(source: &str, offset: usize, error: pest::error::Error<Rule>) -> Self {
+        let (start, end) = match error.location {
+            pest::error::InputLocation::Pos(p) => (p, p + 1),
+            pest::error::InputLocation::Span(span) => span,
+        };
+        let span = AstSpan {
+            start: start + offset,
+            end: end + offset,
+        };
+        let message = match error.variant {
+            pest::error::ErrorVariant::ParsingError { positives, .. } if !positives.is_empty() => {
+                let expected: Vec<String> = positives.iter().map(|x| format!("{x:?}")).collect();
+                format!("Expected {}", expected.join(", "))
+            }
+            pest::error::ErrorVariant::ParsingError { .. } => "Unexpected input".to_string(),
+            pest::error::ErrorVariant::CustomError { message } => message,
+        };
+        GlacierError::Syntax(ErrorInfo::new(source, span, message))
+    }
+
+    pub fn info(&self) -> &ErrorInfo {
+        match self {
+            GlacierError::Syntax(info)
+            | GlacierError::Compile(info)
+            | GlacierError::Runtime(info)
+            | GlacierError::BudgetExhausted(info)
+            | GlacierError::Interrupted(info) => info,
+        }
+    }
+
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            GlacierError::Syntax(_) => "Syntax Error",
+            GlacierError::Compile(_) => "Compile-time Error",
+            GlacierError::Runtime(_) => "Runtime Error",
+            GlacierError::BudgetExhausted(_) => "Budget Exhausted",
+            GlacierError::Interrupted(_) => "Interrupted",
+        }
+    }
+
+    // Human readable form pointing at the offending code, `source` must be the one
+    // the error was reported against
+    pub fn render(&self, source: &str) -> String {
+        let info = self.info();
+        let start = info.span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_str = source[line_start..].split('\n').next().unwrap();
+        let end = info.span.end.clamp(start, line_start + line_str.len());
+
+        format!(
+            "At Line {}:\n{}\n{}{}\n{}:\n    {}",
+            info.line,
+            line_str,
+            " ".repeat(info.column - 1),
+            "^".repeat(source[start..end].chars().count().max(1)),
+            self.kind_name(),
+            info.message
+        )
+    }
+}
+
+impl fmt::Display for GlacierError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let info = self.info();
+        write!(
+            f,
+            "{} at line {}, column {}: {}",
+            self.kind_name(),
+            info.line,
+            info.column,
+            info.message
+        )
+    }
+}
+
+impl std::error::Error for GlacierError {}
@@ -0,0 +1,143 @@
+use std::ops::Deref;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use crate::error::{ErrorInfo, GlacierError};
+use crate::memory::Heap;
+use crate::options::VMOptions;
+use crate::output::OutputSink;
+use crate::parser::parse;
+use crate::value::Value;
+use crate::vm_bc::{RootHandle, VM};
+
+// Interpreter session, state such as global variables is kept between evals
+#[derive(Debug)]
+pub struct Glacier {
+    pub vm: VM,
+}
+
+impl Default for Glacier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Value returned by eval, copied out of the VM's heap together with everything it points to,
+// so it stays valid across later evals and after the Glacier is dropped
+#[derive(Debug)]
+pub struct OwnedValue {
+    value: *mut Value,
+    // Holds the copies, freed with them
+    _heap: Rc<Heap>,
+}
+
+impl OwnedValue {
+    pub fn new(value: &Value) -> Self {
+        let heap = Rc::new(Heap::default());
+        let _guard = heap.enter();
+        OwnedValue {
+            value: value.clone().deep_copy(),
+            _heap: heap.clone(),
+        }
+    }
+}
+
+impl Deref for OwnedValue {
+    type Target = Value;
+
+    fn deref(&self) -> &Value {
+        unsafe { &*self.value }
+    }
+}
+
+impl Glacier {
+    pub fn new() -> Self {
+        Self::with_options(VMOptions::default())
+    }
+
+    pub fn with_options(options: VMOptions) -> Self {
+        let mut vm = VM::with_options(options);
+        vm.repl_mode = true;
+        Glacier { vm }
+    }
+
+    // Runs `source` and returns the value of its last expression statement, or null.
+    // Errors are reported against the accumulated `source()`
+    pub fn eval(&mut self, source: &str) -> Result<OwnedValue, GlacierError> {
+        self.compile(source)?;
+        self.run()
+    }
+
+    // Compiles `source` without running it, VM::save_bytecode can then save the result
+    pub fn compile(&mut self, source: &str) -> Result<(), GlacierError> {
+        self.vm.error = None;
+        self.vm.append_source(source.to_string());
+
+        let ast_ = parse(source)
+            .map_err(|e| GlacierError::syntax(&self.vm.source, self.vm.source_offset, e))?;
+
+        self.vm.compile(&ast_);
+        if let Some(e) = self.vm.error.take() {
+            return Err(e);
+        }
+
+        self.vm.optimize();
+        self.vm.verify().map_err(|e| {
+            // Errors past the end, like a missing return, point at the last instruction
+            let span = self.vm.lines[e.address.min(self.vm.lines.len() - 1)];
+            GlacierError::Compile(ErrorInfo::new(&self.vm.source, span, e.to_string()))
+        })
+    }
+
+    // Runs the last compiled or loaded program, see eval
+    pub fn run(&mut self) -> Result<OwnedValue, GlacierError> {
+        self.vm.error = None;
+        self.vm.execute();
+        if let Some(e) = self.vm.error.take() {
+            return Err(e);
+        }
+
+        Ok(match self.vm.last_popped {
+            Some(lp) => OwnedValue::new(unsafe { &*lp }),
+            None => OwnedValue::new(&Value::Null),
+        })
+    }
+
+    // Makes `function` callable from scripts as `name`, see VM::register_native
+    pub fn register_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        self.vm.register_native(name, arity, function);
+    }
+
+    // Setting the flag makes the running eval return an Interrupted error
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.vm.interrupt_handle()
+    }
+
+    // Where `echo` and `debug` print to, stdout by default
+    pub fn set_output(&mut self, output: OutputSink) {
+        self.vm.output = output;
+    }
+
+    // Copies `value`, such as an array returned by eval, into the VM's heap and keeps it
+    // alive there across later evals
+    pub fn root(&mut self, value: &Value) -> RootHandle {
+        self.vm.add_root(value)
+    }
+
+    pub fn rooted(&self, handle: RootHandle) -> &Value {
+        unsafe { &*self.vm.get_root(handle) }
+    }
+
+    pub fn unroot(&mut self, handle: RootHandle) {
+        self.vm.remove_root(handle);
+    }
+
+    // All code evaluated so far, which error spans refer to
+    pub fn source(&self) -> &str {
+        &self.vm.source
+    }
+}
@@ -0,0 +1,15 @@
+pub mod error;
+pub mod glacier;
+pub mod parser;
+pub mod repl;
+mod tests;
+pub mod vm;
+
+pub use error::GlacierError;
+pub use glacier::{Glacier, OwnedValue};
+pub use vm::options::VMOptions;
+pub use vm::output::OutputSink;
+pub use vm::value::Value;
+
+use parser::*;
+use vm::*;
@@ -1,75 +1,146 @@
-pub mod parser;
-pub mod repl;
-mod tests;
-pub mod vm;
-
-use crate::repl::Repl;
-use parser::*;
-use std::fs::File;
-use std::io::Read;
-use vm::*;
-
-use crate::vm_bc::VM;
-use clap::Parser;
+use std::fs;
+use std::path::Path;
+
+use clap::{Parser, Subcommand};
+use glacier2::repl::Repl;
+use glacier2::vm::bytecode_file::is_bytecode_file;
+use glacier2::{Glacier, VMOptions};
+
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Source or compiled .glcb file to run, starts the REPL if missing
     #[clap(value_parser)]
     file: Option<String>,
+
+    /// Values allocated before a garbage collection cycle starts
+    #[clap(long, value_parser)]
+    gc_threshold: Option<usize>,
+
+    /// Values marked or swept by each garbage collection step
+    #[clap(long, value_parser)]
+    gc_step_work: Option<usize>,
+
+    /// Check the heap for dangling references after every collection
+    #[clap(long, action)]
+    gc_verify: bool,
+
+    /// Print garbage collection statistics after running the file
+    #[clap(long, action)]
+    gc_stats: bool,
+
+    /// Most values alive at once before running out of memory
+    #[clap(long, value_parser)]
+    max_heap: Option<usize>,
+
+    /// Slots on the value stack
+    #[clap(long, value_parser)]
+    stack_size: Option<usize>,
+
+    /// Most nested function calls
+    #[clap(long, value_parser)]
+    max_frames: Option<usize>,
+
+    /// Instructions to run before stopping the program
+    #[clap(long, value_parser)]
+    max_instructions: Option<u64>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compile a file to bytecode that runs without compiling it again
+    Compile {
+        #[clap(value_parser)]
+        file: String,
+
+        /// Where to write the bytecode, the file with a .glcb extension by default
+        #[clap(short, long, value_parser)]
+        output: Option<String>,
+    },
+}
+
+impl Args {
+    fn vm_options(&self) -> VMOptions {
+        let mut options = VMOptions::default().gc_verify(self.gc_verify);
+        if let Some(n) = self.gc_threshold {
+            options = options.gc_threshold(n);
+        }
+        if let Some(n) = self.gc_step_work {
+            options = options.gc_step_work(n);
+        }
+        if let Some(n) = self.max_heap {
+            options = options.max_heap(n);
+        }
+        if let Some(n) = self.stack_size {
+            options = options.stack_size(n);
+        }
+        if let Some(n) = self.max_frames {
+            options = options.max_frames(n);
+        }
+        if let Some(n) = self.max_instructions {
+            options = options.max_instructions(n);
+        }
+        options
+    }
 }
 
 fn main() {
     let args = Args::parse();
 
-    if let Some(path) = args.file {
-        let file = File::open(path);
-        if let Ok(mut file) = file {
-            let mut source = String::new();
-            file.read_to_string(&mut source).unwrap();
-            let mut vm = VM::default();
-            vm.set_source(source.clone());
-
-            let ast_ = parse(source.as_str());
-            if let Ok(ast_) = ast_ {
-                vm.compile(&ast_);
-
-                if let Some(e) = &vm.error {
-                    println!("{e}");
-                    return;
-                }
+    let options = args.vm_options();
 
-                vm.optimize();
+    if let Some(Command::Compile { file, output }) = args.command {
+        let output = output.unwrap_or_else(|| {
+            Path::new(&file)
+                .with_extension("glcb")
+                .to_string_lossy()
+                .into_owned()
+        });
+        let source = match fs::read_to_string(&file) {
+            Ok(source) => source,
+            Err(e) => return println!("Cannot read {file}: {e}"),
+        };
 
-                // println!("{}", vm.disassemble());
+        let mut glacier = Glacier::with_options(options);
+        glacier.vm.repl_mode = false;
+        if let Err(e) = glacier.compile(&source) {
+            return println!("{}", e.render(glacier.source()));
+        }
+        if let Err(e) = fs::write(&output, glacier.vm.save_bytecode()) {
+            println!("Cannot write {output}: {e}");
+        }
+    } else if let Some(path) = args.file {
+        if let Ok(data) = fs::read(&path) {
+            let mut glacier = Glacier::with_options(options);
+            // Values of statements are not wanted when running a file
+            glacier.vm.repl_mode = false;
 
-                vm.execute();
-                if let Some(e) = &vm.error {
-                    println!("{e}");
-                }
-            } else if let Err(e) = ast_ {
-                if let pest::error::LineColLocation::Span(start, end) = e.line_col {
-                    let line_str = source.split('\n').nth(start.0 - 1).unwrap();
-                    println!(
-                        "At Line {}:\n{}\n{}{}\nSyntax Error",
-                        start.0,
-                        line_str,
-                        " ".repeat(start.1 - 1),
-                        "^".repeat(end.1.min(line_str.len()) - start.1),
-                    );
-                } else if let pest::error::LineColLocation::Pos(pos) = e.line_col {
-                    let line_str = source.split('\n').nth(pos.0 - 1).unwrap();
-                    println!(
-                        "At Line {}:\n{}\n{}^\nSyntax Error",
-                        pos.0,
-                        line_str,
-                        " ".repeat(pos.1 - 1),
-                    );
+            let result = if is_bytecode_file(&data) {
+                match glacier.vm.load_bytecode(&data) {
+                    Ok(()) => glacier.run(),
+                    Err(e) => return println!("Cannot load {path}: {e}"),
                 }
+            } else {
+                glacier.eval(&String::from_utf8_lossy(&data))
+            };
+
+            if let Err(e) = result {
+                println!("{}", e.render(glacier.source()));
+            }
+
+            if args.gc_stats {
+                eprintln!("{}", glacier.vm.gc_stats());
             }
         }
     } else {
-        let mut repl_ = Repl::default();
+        let mut repl_ = Repl {
+            glacier: Glacier::with_options(options),
+        };
         repl_.run();
     }
 }
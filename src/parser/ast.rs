@@ -18,18 +18,23 @@ impl<'a> From<Span<'a>> for AstSpan {
 #[derive(Debug, Clone)]
 pub enum Expression<'a> {
     String_(String_),
+    Interpolation(Interpolation<'a>),
     Int(Integer<'a>),
     Float(Float<'a>),
     Bool(Bool),
     Array(Array<'a>),
+    Hash(Hash<'a>),
     GetVar(GetVar<'a>),
     SetVar(Box<SetVar<'a>>),
     Infix(Box<Infix<'a>>),
     Prefix(Box<Prefix<'a>>),
     Index(Box<Index<'a>>),
+    Call(Box<Call<'a>>),
+    Function(Box<FunctionLiteral<'a>>),
 
     If(Box<If<'a>>),
     While(Box<While<'a>>),
+    For(Box<For<'a>>),
     Do(Box<Do<'a>>),
 }
 
@@ -48,6 +53,14 @@ pub struct While<'a> {
     pub pos: AstSpan,
 }
 
+#[derive(Debug, Clone)]
+pub struct For<'a> {
+    pub variable: &'a str,
+    pub iterable: Expression<'a>,
+    pub body: Program<'a>,
+    pub pos: AstSpan,
+}
+
 #[derive(Debug, Clone)]
 pub struct Do<'a> {
     pub body: Program<'a>,
@@ -75,6 +88,13 @@ pub struct String_ {
     pub pos: AstSpan,
 }
 
+// String literal with embedded expressions, parts are printed and joined in order
+#[derive(Debug, Clone)]
+pub struct Interpolation<'a> {
+    pub parts: Vec<Expression<'a>>,
+    pub pos: AstSpan,
+}
+
 #[derive(Debug, Clone)]
 pub struct Integer<'a> {
     pub value: &'a str,
@@ -99,6 +119,12 @@ pub struct Array<'a> {
     pub pos: AstSpan,
 }
 
+#[derive(Debug, Clone)]
+pub struct Hash<'a> {
+    pub pairs: Vec<(Expression<'a>, Expression<'a>)>,
+    pub pos: AstSpan,
+}
+
 #[derive(Debug, Clone)]
 pub struct GetVar<'a> {
     pub name: &'a str,
@@ -119,6 +145,20 @@ pub struct Index<'a> {
     pub pos: AstSpan,
 }
 
+#[derive(Debug, Clone)]
+pub struct Call<'a> {
+    pub callee: Expression<'a>,
+    pub arguments: Vec<Expression<'a>>,
+    pub pos: AstSpan,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionLiteral<'a> {
+    pub parameters: Vec<&'a str>,
+    pub body: Program<'a>,
+    pub pos: AstSpan,
+}
+
 #[derive(Debug, Clone)]
 pub enum Statement<'a> {
     ExprStmt(ExprStmt<'a>),
@@ -126,6 +166,8 @@ pub enum Statement<'a> {
     EchoPrint(EchoPrint<'a>),
     Break(Break),
     Next(Next),
+    Return(Return<'a>),
+    FunctionDef(Box<FunctionDef<'a>>),
     PointerAssign(Box<PointerAssign<'a>>),
 }
 
@@ -157,6 +199,20 @@ pub struct Next {
     pub pos: AstSpan,
 }
 
+#[derive(Debug, Clone)]
+pub struct Return<'a> {
+    pub value: Option<Expression<'a>>,
+    pub pos: AstSpan,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionDef<'a> {
+    pub name: &'a str,
+    pub parameters: Vec<&'a str>,
+    pub body: Program<'a>,
+    pub pos: AstSpan,
+}
+
 #[derive(Debug, Clone)]
 pub struct PointerAssign<'a> {
     pub ptr: Expression<'a>,
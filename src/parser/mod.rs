@@ -14,6 +14,7 @@ lazy_static! {
         use Assoc::*;
 
         PrecClimber::new(vec![
+            Operator::new(inclusive_range, Left) | Operator::new(exclusive_range, Left),
             Operator::new(log_or, Left),
             Operator::new(log_and, Left),
             Operator::new(dbeq, Left) | Operator::new(neq, Left),
@@ -41,35 +42,56 @@ fn infix<'a>(lhs: Expression<'a>, op: Pair<'a, Rule>, rhs: Expression<'a>) -> Ex
     }))
 }
 
+fn unescape(literal: &str) -> String {
+    let mut s = String::new();
+    let mut escape_mode = false;
+    for c in literal.chars() {
+        if escape_mode {
+            match c {
+                'n' => s.push('\n'),
+                't' => s.push('\t'),
+                'r' => s.push('\r'),
+                '0' => s.push('\0'),
+                '"' => s.push('"'),
+                '\'' => s.push('\''),
+                '\\' => s.push('\\'),
+                '#' => s.push('#'),
+                _ => (),
+            }
+            escape_mode = false;
+        } else if c == '\\' {
+            escape_mode = true;
+        } else {
+            s.push(c);
+        }
+    }
+    s
+}
+
 fn others(pair: Pair<Rule>) -> Expression {
     match pair.as_rule() {
-        Rule::string_literal => Expression::String_(String_ {
-            value: {
-                let mut s = String::new();
-                let mut escape_mode = false;
-                for c in pair.as_str()[1..pair.as_str().len() - 1].chars() {
-                    if escape_mode {
-                        match c {
-                            'n' => s.push('\n'),
-                            't' => s.push('\t'),
-                            'r' => s.push('\r'),
-                            '0' => s.push('\0'),
-                            '"' => s.push('"'),
-                            '\'' => s.push('\''),
-                            '\\' => s.push('\\'),
-                            _ => (),
-                        }
-                        escape_mode = false;
-                    } else if c == '\\' {
-                        escape_mode = true;
-                    } else {
-                        s.push(c);
-                    }
-                }
-                s
-            },
-            pos: pair.as_span().into(),
-        }),
+        Rule::string_literal => {
+            let inner = pair.clone().into_inner();
+            if inner.clone().all(|x| x.as_rule() == Rule::string_part) {
+                return Expression::String_(String_ {
+                    value: unescape(&pair.as_str()[1..pair.as_str().len() - 1]),
+                    pos: pair.as_span().into(),
+                });
+            }
+
+            Expression::Interpolation(Interpolation {
+                parts: inner
+                    .map(|x| match x.as_rule() {
+                        Rule::string_part => Expression::String_(String_ {
+                            value: unescape(x.as_str()),
+                            pos: x.as_span().into(),
+                        }),
+                        _ => parse_expression(x.into_inner().next().unwrap()),
+                    })
+                    .collect(),
+                pos: pair.as_span().into(),
+            })
+        }
 
         Rule::integer => Expression::Int(Integer {
             value: pair.as_str(),
@@ -89,6 +111,20 @@ fn others(pair: Pair<Rule>) -> Expression {
             })
         }
 
+        Rule::hash => {
+            let inner = pair.clone().into_inner();
+            Expression::Hash(Hash {
+                pairs: inner
+                    .map(|x| {
+                        let mut kv = x.into_inner();
+                        let key = parse_expression(kv.next().unwrap());
+                        (key, parse_expression(kv.next().unwrap()))
+                    })
+                    .collect(),
+                pos: pair.as_span().into(),
+            })
+        }
+
         Rule::false_expr => Expression::Bool(Bool {
             value: false,
             pos: pair.as_span().into(),
@@ -150,6 +186,19 @@ fn others(pair: Pair<Rule>) -> Expression {
             }))
         }
 
+        Rule::for_loop => {
+            let mut inner = pair.clone().into_inner();
+            let variable = inner.next().unwrap().as_str();
+            let iterable = inner.next().unwrap();
+            let res = inner.next().unwrap();
+            Expression::For(Box::new(For {
+                variable,
+                iterable: parse_expression(iterable),
+                body: parse_program(res.into_inner()),
+                pos: pair.as_span().into(),
+            }))
+        }
+
         Rule::do_block => {
             let mut inner = pair.clone().into_inner();
             let res = inner.next().unwrap();
@@ -176,33 +225,30 @@ fn others(pair: Pair<Rule>) -> Expression {
         }
         Rule::suffix => {
             let mut inner = pair.clone().into_inner();
-            let res = inner.next().unwrap();
-            let mut args_iter = inner;
+            let mut callee = parse_expression(inner.next().unwrap());
 
-            let n = args_iter.next().unwrap();
-            let mut callee = match n.as_rule() {
-                Rule::indexing => Expression::Index(Box::new(Index {
-                    callee: parse_expression(res),
-                    index: parse_expression(n.into_inner().next().unwrap()),
-                    pos: pair.as_span().into(),
-                })),
-                _ => unreachable!(),
-            };
-
-            for xx in args_iter {
-                callee = match xx.as_rule() {
-                    Rule::indexing => Expression::Index(Box::new(Index {
-                        callee,
-                        index: parse_expression(xx.into_inner().next().unwrap()),
-                        pos: pair.as_span().into(),
-                    })),
-                    _ => unreachable!(),
-                }
+            for xx in inner {
+                callee = parse_suffix(callee, xx, pair.as_span().into());
             }
 
             callee
         }
 
+        Rule::function_literal => {
+            let mut inner = pair.clone().into_inner();
+            let mut n = inner.next().unwrap();
+            let mut params = vec![];
+            if n.as_rule() == Rule::parameters {
+                params = n.into_inner().map(|x| x.as_str()).collect();
+                n = inner.next().unwrap();
+            }
+            Expression::Function(Box::new(FunctionLiteral {
+                parameters: params,
+                body: parse_program(n.into_inner()),
+                pos: pair.as_span().into(),
+            }))
+        }
+
         Rule::expression => climb(pair),
         _ => {
             dbg!(pair.as_rule());
@@ -211,6 +257,22 @@ fn others(pair: Pair<Rule>) -> Expression {
     }
 }
 
+fn parse_suffix<'a>(callee: Expression<'a>, pair: Pair<'a, Rule>, pos: AstSpan) -> Expression<'a> {
+    match pair.as_rule() {
+        Rule::indexing => Expression::Index(Box::new(Index {
+            callee,
+            index: parse_expression(pair.into_inner().next().unwrap()),
+            pos,
+        })),
+        Rule::call => Expression::Call(Box::new(Call {
+            callee,
+            arguments: pair.into_inner().map(parse_expression).collect(),
+            pos,
+        })),
+        _ => unreachable!(),
+    }
+}
+
 pub fn climb(pair: Pair<Rule>) -> Expression {
     //dbg!(&pair);
     PREC_CLIMBER.climb(pair.into_inner(), others, infix)
@@ -260,31 +322,36 @@ fn parse_statement(pair: Pair<Rule>) -> Statement {
             pos: pair.as_span().into(),
         }),
 
+        Rule::return_stmt => Statement::Return(Return {
+            value: pair.clone().into_inner().next().map(parse_expression),
+            pos: pair.as_span().into(),
+        }),
+
+        Rule::function_def => {
+            let mut inner = pair.clone().into_inner();
+            let name = inner.next().unwrap().as_str();
+            let mut n = inner.next().unwrap();
+            let mut params = vec![];
+            if n.as_rule() == Rule::parameters {
+                params = n.into_inner().map(|x| x.as_str()).collect();
+                n = inner.next().unwrap();
+            }
+            Statement::FunctionDef(Box::new(FunctionDef {
+                name,
+                parameters: params,
+                body: parse_program(n.into_inner()),
+                pos: pair.as_span().into(),
+            }))
+        }
+
         Rule::suffix_assign => {
             let mut inner = pair.clone().into_inner();
-            let res = inner.next().unwrap();
+            let mut callee = parse_expression(inner.next().unwrap());
             let mut args_iter = inner.peekable();
 
-            let n = args_iter.next().unwrap();
-            let mut callee = match n.as_rule() {
-                Rule::indexing => Expression::Index(Box::new(Index {
-                    callee: parse_expression(res),
-                    index: parse_expression(n.into_inner().next().unwrap()),
-                    pos: pair.as_span().into(),
-                })),
-                _ => unreachable!(),
-            };
-
             while let Some(xx) = args_iter.next() {
                 if args_iter.peek().is_some() {
-                    callee = match xx.as_rule() {
-                        Rule::indexing => Expression::Index(Box::new(Index {
-                            callee,
-                            index: parse_expression(xx.into_inner().next().unwrap()),
-                            pos: pair.as_span().into(),
-                        })),
-                        _ => unreachable!(),
-                    }
+                    callee = parse_suffix(callee, xx, pair.as_span().into());
                 } else {
                     return Statement::PointerAssign(Box::new(PointerAssign {
                         ptr: callee,
@@ -311,6 +378,8 @@ fn parse_program(res: Pairs<Rule>) -> Program {
             | Rule::echo_print
             | Rule::break_stmt
             | Rule::next_stmt
+            | Rule::return_stmt
+            | Rule::function_def
             | Rule::suffix_assign => ast.push(parse_statement(pair)),
             _ => {}
         }
@@ -318,7 +387,8 @@ fn parse_program(res: Pairs<Rule>) -> Program {
     ast
 }
 
-pub fn parse(code: &str) -> Result<Program, pest::error::Error<Rule>> {
+#[allow(clippy::result_large_err)]
+pub fn parse(code: &str) -> Result<Program<'_>, pest::error::Error<Rule>> {
     let res = GlacierParser::parse(Rule::program, code);
     match res {
         Ok(res) => {
@@ -0,0 +1,181 @@
+// Runs every .glc file under the golden directories and compares what it prints,
+// and the error it stops with, against its expectations:
+//
+// - `# expect: <line>` comments in the file, one per printed line, and
+//   `# expect error: <message>` for the message of the error it stops with
+// - otherwise `<name>.out` with the printed text and `<name>.err` with the rendered error,
+//   both missing meaning the file prints nothing and succeeds
+//
+// Setting GLACIER_BLESS=1 rewrites the .out and .err files of files without
+// inline expectations from what they do now.
+
+use std::cell::RefCell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::{Glacier, OutputSink};
+
+const DIRECTORIES: [&str; 2] = ["src/tests/golden", "examples"];
+
+// What a file printed, and its rendered error and error message if it failed
+struct Outcome {
+    output: String,
+    error: Option<(String, String)>,
+}
+
+fn run(source: &str) -> Outcome {
+    let output = Rc::new(RefCell::new(String::new()));
+    let sink = output.clone();
+
+    let mut glacier = Glacier::new();
+    glacier.vm.repl_mode = false;
+    glacier.set_output(OutputSink::callback(move |line| {
+        let mut output = sink.borrow_mut();
+        output.push_str(line);
+        output.push('\n');
+    }));
+
+    let error = glacier
+        .eval(source)
+        .err()
+        .map(|e| (e.render(glacier.source()), e.info().message.clone()));
+    let output = output.take();
+    Outcome { output, error }
+}
+
+fn files(dir: &Path, found: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files(&path, found);
+        } else if path.extension().is_some_and(|e| e == "glc") {
+            found.push(path);
+        }
+    }
+}
+
+// Lines of both, marking the ones that differ with - for expected and + for actual
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut result = String::new();
+
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => result += &format!("    {e}\n"),
+            (e, a) => {
+                if let Some(e) = e {
+                    result += &format!("  - {e}\n");
+                }
+                if let Some(a) = a {
+                    result += &format!("  + {a}\n");
+                }
+            }
+        }
+    }
+    result
+}
+
+fn read_or_empty(path: &Path) -> String {
+    fs::read_to_string(path).unwrap_or_default()
+}
+
+// Describes how `path` does not match its expectations, if it does not
+fn check(path: &Path, bless: bool) -> Option<String> {
+    let source = fs::read_to_string(path).unwrap();
+    let outcome = run(&source);
+    let mut problems = String::new();
+
+    let inline: Vec<&str> = source
+        .lines()
+        .filter_map(|line| line.split_once("# expect").map(|(_, e)| e))
+        .collect();
+
+    if !inline.is_empty() {
+        let mut output = String::new();
+        let mut error = None;
+        for expectation in inline {
+            if let Some(line) = expectation.strip_prefix(": ") {
+                output += line;
+                output.push('\n');
+            } else if let Some(message) = expectation.strip_prefix(" error: ") {
+                error = Some(message.to_string());
+            } else {
+                problems += &format!("Unknown expectation: # expect{expectation}\n");
+            }
+        }
+
+        if outcome.output != output {
+            problems += &format!("Output differs:\n{}", diff(&output, &outcome.output));
+        }
+        let message = outcome.error.as_ref().map(|(_, m)| m.clone());
+        if message != error {
+            problems += &format!(
+                "Expected error: {}\nGot error: {}\n",
+                error.as_deref().unwrap_or("none"),
+                outcome.error.as_ref().map_or("none", |(e, _)| e)
+            );
+        }
+    } else {
+        let out_path = path.with_extension("out");
+        let err_path = path.with_extension("err");
+        let rendered = outcome.error.map(|(e, _)| e + "\n");
+
+        if bless {
+            if outcome.output.is_empty() {
+                let _ = fs::remove_file(&out_path);
+            } else {
+                fs::write(&out_path, &outcome.output).unwrap();
+            }
+            match &rendered {
+                Some(e) => fs::write(&err_path, e).unwrap(),
+                None => {
+                    let _ = fs::remove_file(&err_path);
+                }
+            }
+            return None;
+        }
+
+        let output = read_or_empty(&out_path);
+        if outcome.output != output {
+            problems += &format!("Output differs:\n{}", diff(&output, &outcome.output));
+        }
+        let error = read_or_empty(&err_path);
+        let rendered = rendered.unwrap_or_default();
+        if rendered != error {
+            problems += &format!("Error differs:\n{}", diff(&error, &rendered));
+        }
+    }
+
+    (!problems.is_empty()).then_some(problems)
+}
+
+#[test]
+fn test_golden() {
+    let bless = std::env::var("GLACIER_BLESS").is_ok_and(|v| v == "1");
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+
+    let mut found = Vec::new();
+    for dir in DIRECTORIES {
+        files(&root.join(dir), &mut found);
+    }
+    found.sort();
+    assert!(!found.is_empty());
+
+    let mut failures = Vec::new();
+    for path in found.iter() {
+        if let Some(problems) = check(path, bless) {
+            let name = path.strip_prefix(root).unwrap().display();
+            failures.push(format!("{name}:\n{problems}"));
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} golden files failed, run with GLACIER_BLESS=1 to accept new output\n\n{}",
+        failures.len(),
+        found.len(),
+        failures.join("\n")
+    );
+}
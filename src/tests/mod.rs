@@ -1,54 +1,40 @@
 #[cfg(test)]
-mod testcases {
-    use crate::value::Value;
-    use crate::{parse, VM};
-
-    fn test_file(content: String, expected: Value) -> bool {
-        let mut vm = VM {
-            repl_mode: true,
-            ..Default::default()
-        };
-        vm.set_source(content.clone());
+mod golden;
 
-        let ast_ = parse(content.as_str());
-        if let Ok(ast_) = ast_ {
-            vm.compile(&ast_);
-
-            if let Some(e) = &vm.error {
-                println!("{e}");
-                return false;
-            }
+#[cfg(test)]
+mod testcases {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
 
-            vm.optimize();
-            vm.execute();
+    use crate::vm::value::Function;
+    use crate::{Glacier, GlacierError, OutputSink, VMOptions, Value};
 
-            if let Some(e) = &vm.error {
-                println!("{e}");
-                return false;
-            }
+    // Collection cycles start and advance all the time, to catch missing write barriers
+    fn stressed_gc() -> Glacier {
+        Glacier::with_options(
+            VMOptions::default()
+                .gc_threshold(8)
+                .gc_step_work(4)
+                .gc_verify(true),
+        )
+    }
 
-            if let Some(lp) = &vm.last_popped {
-                if unsafe { &**lp }.is_equal(&expected) {
-                    true
-                } else {
-                    println!(
-                        "Expected: {:?}, got: {:?}",
-                        expected.debug_format(),
-                        unsafe { &**lp }.debug_format()
-                    );
-                    false
-                }
-            } else {
+    fn test_file(content: String, expected: Value, mut glacier: Glacier) -> bool {
+        match glacier.eval(&content) {
+            Ok(v) if v.is_equal(&expected) => true,
+            Ok(v) => {
                 println!(
                     "Expected: {:?}, got: {:?}",
                     expected.debug_format(),
-                    "nothing"
+                    v.debug_format()
                 );
                 false
             }
-        } else {
-            println!("Syntax Error");
-            false
+            Err(e) => {
+                println!("{}", e.render(&content));
+                false
+            }
         }
     }
 
@@ -58,13 +44,910 @@ mod testcases {
             (include_str!("tests/1_arithmetic.glc"), Value::Int(682)),
             (include_str!("tests/2_cf1.glc"), Value::Int(5)),
             (include_str!("tests/3_cf2.glc"), Value::Bool(true)),
+            (include_str!("tests/4_functions.glc"), Value::Bool(true)),
+            (include_str!("tests/5_closures.glc"), Value::Bool(true)),
+            (include_str!("tests/6_hashes.glc"), Value::Bool(true)),
+            (include_str!("tests/7_for.glc"), Value::Bool(true)),
+            (include_str!("tests/8_ranges.glc"), Value::Bool(true)),
+            (include_str!("tests/9_indexing.glc"), Value::Bool(true)),
+            (
+                include_str!("tests/10_interpolation.glc"),
+                Value::Bool(true),
+            ),
         ];
 
-        let mut i = 1;
-        for (content, expected) in testsuite.iter() {
+        for (i, (content, expected)) in (1..).zip(testsuite.iter()) {
             println!("Testing: {i}");
-            assert!(test_file(content.to_string(), expected.clone()));
-            i += 1;
+            assert!(test_file(
+                content.to_string(),
+                expected.clone(),
+                Glacier::new()
+            ));
+            assert!(test_file(
+                content.to_string(),
+                expected.clone(),
+                stressed_gc()
+            ));
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let cases = [
+            ("a = 1 +", "Syntax", 1, 8),
+            ("x = 1\ndebug y", "Compile", 2, 7),
+            ("a = [1, 2]\n  \"é\" + a[5]", "Runtime", 2, 9),
+            ("def f(a) a end\nf(1, 2)", "Runtime", 2, 1),
+        ];
+
+        for (content, kind, line, column) in cases {
+            let e = Glacier::new().eval(content).expect_err(content);
+            let info = e.info();
+            let got = match e {
+                GlacierError::Syntax(_) => "Syntax",
+                GlacierError::Compile(_) => "Compile",
+                GlacierError::Runtime(_) => "Runtime",
+                _ => "Stopped",
+            };
+            assert_eq!(
+                (got, info.line, info.column),
+                (kind, line, column),
+                "{content}"
+            );
+        }
+
+        let e = Glacier::new().eval("\"é\" + [1][3]").unwrap_err();
+        assert_eq!(
+            e.render("\"é\" + [1][3]"),
+            "At Line 1:\n\"é\" + [1][3]\n      ^^^^^^\nRuntime Error:\n    Index out of range: 3 for length 1"
+        );
+        assert_eq!(e.info().message, "Index out of range: 3 for length 1");
+
+        let e = Glacier::new()
+            .eval("[1, 2][0..9223372036854775807]")
+            .unwrap_err();
+        assert_eq!(
+            e.info().message,
+            "Slice out of range: 0..9223372036854775807 for length 2"
+        );
+
+        // A state inside a character or past the end is an error, not a panic
+        let s = Value::String("é".to_string());
+        assert!(s.iterate(1).is_err());
+        assert!(s.iterate(5).is_err());
+    }
+
+    #[test]
+    fn test_session() {
+        let mut glacier = Glacier::new();
+        assert!(glacier.eval("def sq(x) x * x end; n = 3").is_ok());
+        assert!(glacier.eval("sq(n) + ").is_err());
+        assert!(glacier.eval("m").is_err());
+        assert!(glacier.eval("[1][n]").is_err());
+        assert!(glacier.eval("sq(n) + 1").unwrap().is_equal(&Value::Int(10)));
+        assert!(glacier.eval("n = 1").unwrap().is_equal(&Value::Int(1)));
+
+        let e = glacier.eval("\nn + true").unwrap_err();
+        assert_eq!((e.info().line, e.info().column), (8, 3));
+        assert!(e
+            .render(glacier.source())
+            .starts_with("At Line 8:\nn + true\n  ^\n"));
+    }
+
+    #[test]
+    fn test_natives() {
+        let mut glacier = Glacier::new();
+        glacier.register_native("add", 2, |args| match (&args[0], &args[1]) {
+            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a + b)),
+            _ => Err("add expects two ints".to_string()),
+        });
+        glacier.register_native("size", 1, |args| match &args[0] {
+            Value::Array(a) => Ok(Value::Int(a.len() as i64)),
+            Value::String(s) => Ok(Value::Int(s.chars().count() as i64)),
+            x => Err(format!("Cannot take the size of {}", x.type_name())),
+        });
+
+        let result = glacier.eval(
+            "def total(a) s = 0; for x in a: s = add(s, x); s end\n\
+             f = size\n\
+             [total([1, 2, 3]), f(\"héllo\"), size([[], []]), add]",
+        );
+        assert_eq!(
+            result.unwrap().debug_format(),
+            "[6, 5, 2, <native function add>]"
+        );
+
+        let e = glacier.eval("x = 1\ny = add(x, 2.5)").unwrap_err();
+        assert!(matches!(e, GlacierError::Runtime(_)));
+        assert_eq!(e.info().message, "add expects two ints");
+        assert_eq!((e.info().line, e.info().column), (5, 5));
+
+        let e = glacier.eval("size(1, 2)").unwrap_err();
+        assert_eq!(e.info().message, "Function size expects 1 arguments, got 2");
+        assert!(glacier.eval("size(true)").is_err());
+        assert!(glacier
+            .eval("size(\"ab\") == 2")
+            .unwrap()
+            .is_equal(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn test_gc_reclaims() {
+        let mut glacier = Glacier::new();
+        glacier.register_native("f", 0, |_| Ok(Value::Null));
+        let native = match &*glacier.eval("f").unwrap() {
+            Value::NativeFunction(n) => n.clone(),
+            _ => unreachable!(),
+        };
+
+        let before = glacier.vm.heap.live();
+        glacier
+            .eval("for i in 10000; g = f; s = \"#{i} glacier\"; a = [s, [i], {i: s}] end")
+            .unwrap();
+        // Collection is incremental, so some garbage is still around after eval
+        glacier.vm.gc_recollect();
+
+        // Each iteration copies the native into g, dropping the copy must release it
+        assert!(
+            Rc::strong_count(&native) < 10,
+            "{}",
+            Rc::strong_count(&native)
+        );
+        let live = glacier.vm.heap.live();
+        assert!(live - before < 100, "{}", live - before);
+    }
+
+    #[test]
+    fn test_separate_heaps() {
+        let mut g1 = Glacier::new();
+        let mut g2 = Glacier::new();
+        g1.eval("a = [\"kept\", [1, 2], {1: \"x\"}]").unwrap();
+
+        // Collecting g2's garbage must not touch g1's values
+        g2.eval("for i in 1000; b = [i, \"#{i}\"]; end").unwrap();
+        assert!(g1
+            .eval("a == [\"kept\", [1, 2], {1: \"x\"}]")
+            .unwrap()
+            .is_equal(&Value::Bool(true)));
+
+        let live = g2.vm.heap.live();
+        drop(g1);
+        assert_eq!(g2.vm.heap.live(), live);
+    }
+
+    #[test]
+    fn test_incremental_gc() {
+        let mut glacier = stressed_gc();
+        let result = glacier.eval(
+            "old = [[0], {0: 0}]
+             for i in 3000
+                 x = [i, \"#{i}\"]
+                 old[0] = x
+                 h = {i: x}
+                 old[1] = h
+                 x = 0
+                 h = 0
+             end
+             old == [[2999, \"2999\"], {2999: [2999, \"2999\"]}]",
+        );
+        assert!(result.unwrap().is_equal(&Value::Bool(true)));
+
+        // Moves a value that is not traced yet into one that is, then cuts the old path to it
+        let mut glacier = Glacier::with_options(
+            VMOptions::default()
+                .gc_threshold(usize::MAX)
+                .gc_step_work(0),
+        );
+        glacier.eval("t = [[7, \"w\"]]; b = [0]").unwrap();
+        let heap = glacier.vm.heap.clone();
+        heap.start_marking();
+        for p in glacier.vm.stack.iter() {
+            heap.shade(*p);
+        }
+        // Traces b and its element, leaving t grey
+        assert!(!heap.mark_step(3));
+        glacier.eval("b[0] = t[0]; t[0] = 0").unwrap();
+        glacier
+            .eval("for i in 100; junk = [i, \"#{i}\"]; end")
+            .unwrap();
+        assert_eq!(glacier.eval("b").unwrap().debug_format(), "[[7, \"w\"]]");
+
+        let mut glacier = Glacier::with_options(VMOptions::default().gc_threshold(usize::MAX));
+        glacier.eval("a = [true] * 100000").unwrap();
+
+        // A big array is traced and swept over many bounded steps
+        let heap = glacier.vm.heap.clone();
+        heap.start_marking();
+        heap.shade(glacier.vm.stack[0]);
+        let mut steps = 1;
+        while !heap.mark_step(1000) {
+            steps += 1;
+        }
+        assert!(steps >= 100, "{steps}");
+
+        heap.start_sweeping();
+        steps = 1;
+        while !heap.sweep_step(1000) {
+            steps += 1;
         }
+        assert!(steps >= 100, "{steps}");
+        assert!(glacier
+            .eval("a[-1] && a[0]")
+            .unwrap()
+            .is_equal(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn test_host_roots() {
+        let mut glacier = stressed_gc();
+        let kept = glacier.eval("[1, [2, \"two\"], {3: \"three\"}]").unwrap();
+        let handle = glacier.root(&kept);
+        let live = glacier.vm.heap.live();
+
+        glacier
+            .eval("for i in 1000; junk = [i, \"#{i}\", {i: [i]}]; end; junk = 0")
+            .unwrap();
+        assert_eq!(
+            glacier.rooted(handle).debug_format(),
+            "[1, [2, \"two\"], {3: \"three\"}]"
+        );
+
+        // Once released, the value and everything it points to is reclaimed
+        glacier.unroot(handle);
+        glacier.vm.gc_recollect();
+        assert!(glacier.vm.heap.live() < live - 5);
+
+        let other = glacier.root(&Value::Int(1));
+        assert_eq!(other, handle);
+    }
+
+    #[test]
+    fn test_owned_results() {
+        // The result is copied out of the heap, so it outlives the session
+        let v = {
+            Glacier::new()
+                .eval("[\"hello\", [1, 2], {3: [4]}]")
+                .unwrap()
+        };
+        assert_eq!(v.debug_format(), "[\"hello\", [1, 2], {3: [4]}]");
+
+        let mut glacier = stressed_gc();
+        let kept = glacier.eval("a = [[1], \"x\"]").unwrap();
+        glacier
+            .eval("a[0][0] = 2; for i in 1000; junk = [i, \"#{i}\"]; end")
+            .unwrap();
+        assert_eq!(kept.debug_format(), "[[1], \"x\"]");
+
+        assert!(Glacier::default().vm.repl_mode);
+    }
+
+    #[test]
+    fn test_output() {
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let mut glacier = Glacier::new();
+        let sink = lines.clone();
+        glacier.set_output(OutputSink::callback(move |line| {
+            sink.borrow_mut().push(line.to_string())
+        }));
+        glacier
+            .eval("echo \"a\"; debug \"a\"; x = [1, \"b\", {2: 2.5}]; echo x; debug x; echo \"#{1}\\nz\"")
+            .unwrap();
+        assert_eq!(
+            *lines.borrow(),
+            [
+                "a",
+                "\"a\"",
+                "[1, \"b\", {2: 2.5}]",
+                "[1, \"b\", {2: 2.5}]",
+                "1\nz"
+            ]
+        );
+
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let bytes = Rc::new(RefCell::new(Vec::new()));
+        glacier.set_output(OutputSink::writer(Shared(bytes.clone())));
+        glacier.eval("for i in 3: echo i * 2").unwrap();
+        assert_eq!(String::from_utf8(bytes.take()).unwrap(), "0\n2\n4\n");
+    }
+
+    #[test]
+    fn test_bytecode_file() {
+        let source = "def counter(step)\n\
+                      n = 0\n\
+                      fn() n = n + step; n end\n\
+                      end\n\
+                      c = counter(1.5)\n\
+                      c(); c()\n\
+                      words = [\"a\", \"#{c()}\", -7]\n\
+                      words[1] + \"!\"";
+        let mut compiler = Glacier::new();
+        compiler.compile(source).unwrap();
+        let data = compiler.vm.save_bytecode();
+
+        let mut glacier = Glacier::new();
+        glacier.vm.load_bytecode(&data).unwrap();
+        assert_eq!(glacier.run().unwrap().debug_format(), "\"4.5!\"");
+
+        // Errors still point into the saved source
+        let mut compiler = Glacier::new();
+        compiler.compile("x = 1\ny = [x][2]").unwrap();
+        let mut glacier = Glacier::new();
+        glacier
+            .vm
+            .load_bytecode(&compiler.vm.save_bytecode())
+            .unwrap();
+        let e = glacier.run().unwrap_err();
+        assert_eq!((e.info().line, e.info().column), (2, 5));
+        assert!(e
+            .render(glacier.source())
+            .starts_with("At Line 2:\ny = [x][2]\n"));
+
+        let mut corrupted = data.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let mut newer = data.clone();
+        newer[4] += 1;
+        for (bytes, message) in [
+            (
+                &corrupted[..],
+                "Bytecode file is corrupted: checksum mismatch",
+            ),
+            (&newer[..], "Bytecode file has format version 4, expected 3"),
+            (&data[..7], "Bytecode file is truncated"),
+            (b"x = 1", "Not a Glacier bytecode file"),
+        ] {
+            assert_eq!(Glacier::new().vm.load_bytecode(bytes).unwrap_err(), message);
+        }
+
+        // The globals are reserved up front, so they must fit on the stack
+        let mut small = Glacier::with_options(VMOptions::default().stack_size(1));
+        assert_eq!(
+            small.vm.load_bytecode(&data).unwrap_err(),
+            "Bytecode file needs 3 global slots, more than the stack size of 1"
+        );
+    }
+
+    #[test]
+    fn test_verifier() {
+        use crate::vm::bytecode::*;
+        use crate::vm::verifier::{VerifyError, VerifyErrorKind};
+
+        let verify = |bytecodes: Vec<Byte>| {
+            let mut glacier = Glacier::new();
+            glacier.compile("x = 1; y = 2").unwrap();
+            glacier.vm.lines = vec![glacier.vm.lines[0]; bytecodes.len()];
+            glacier.vm.bytecodes = bytecodes;
+            glacier.vm.entry = 0;
+            glacier.vm.verify().map_err(|e| (e.address, e.kind))
+        };
+
+        assert_eq!(verify(vec![LOAD_CONST, 3, REPLACE, 1]), Ok(()));
+        assert_eq!(
+            verify(vec![LOAD_CONST, 3, JUMP, 9]),
+            Err((2, VerifyErrorKind::JumpOutOfRange(9)))
+        );
+        assert_eq!(
+            verify(vec![LOAD_CONST, 3, JUMP, 1]),
+            Err((1, VerifyErrorKind::InsideInstruction(1)))
+        );
+        assert_eq!(
+            verify(vec![LOAD_CONST, 900]),
+            Err((0, VerifyErrorKind::ConstantOutOfRange(900)))
+        );
+        assert_eq!(
+            verify(vec![LOAD_LOCAL, 2]),
+            Err((0, VerifyErrorKind::LocalOutOfRange(2)))
+        );
+        assert_eq!(
+            verify(vec![LOAD_CONST, 3, BINARY_ADD]),
+            Err((2, VerifyErrorKind::StackUnderflow(2, 1)))
+        );
+        assert_eq!(
+            verify(vec![MAKE_ARRAY]),
+            Err((0, VerifyErrorKind::MissingOperands))
+        );
+        assert_eq!(
+            verify(vec![200]),
+            Err((0, VerifyErrorKind::UnknownBytecode(200)))
+        );
+        // Pushes a value only when the condition holds, then both paths meet
+        assert_eq!(
+            verify(vec![
+                LOAD_CONST,
+                1,
+                JUMP_IF_FALSE,
+                6,
+                LOAD_CONST,
+                3,
+                NOOP,
+                POP_LAST
+            ]),
+            Err((6, VerifyErrorKind::StackMismatch(1, 0)))
+        );
+
+        let e = VerifyError {
+            address: 6,
+            kind: VerifyErrorKind::StackMismatch(1, 0),
+        };
+        assert_eq!(
+            e.to_string(),
+            "Invalid bytecode at 0006: stack depth is 1 on one path and 0 on another"
+        );
+
+        // Functions are checked from their own address, with their own locals
+        let mut glacier = Glacier::new();
+        glacier.compile("def f(a) a + 1 end").unwrap();
+        let address = glacier
+            .vm
+            .constants
+            .iter()
+            .find_map(|c| match c {
+                Value::Function(f) => Some(f.address),
+                _ => None,
+            })
+            .unwrap();
+        glacier.vm.bytecodes[address + 1] = 5;
+        assert_eq!(
+            glacier.vm.verify().unwrap_err().kind,
+            VerifyErrorKind::LocalOutOfRange(5)
+        );
+        glacier.vm.bytecodes[address + 1] = 0;
+        assert!(glacier.vm.verify().is_ok());
+
+        assert_eq!(
+            verify(vec![LOAD_CONST, 3, RETURN]),
+            Err((2, VerifyErrorKind::ReturnOutsideFunction))
+        );
+
+        fn function<'a>(glacier: &'a mut Glacier, name: &str) -> &'a mut Function {
+            glacier
+                .vm
+                .constants
+                .iter_mut()
+                .find_map(|c| match c {
+                    Value::Function(f) if f.name == name => Some(Rc::make_mut(f)),
+                    _ => None,
+                })
+                .unwrap()
+        }
+
+        let mut glacier = Glacier::new();
+        glacier.compile("def f(a) a + 1 end").unwrap();
+        function(&mut glacier, "f").address = 10000;
+        assert_eq!(
+            glacier.vm.verify().unwrap_err(),
+            VerifyError {
+                address: 10000,
+                kind: VerifyErrorKind::FunctionOutOfRange(4)
+            }
+        );
+
+        // Captures must name locals or upvalues of the code making the closure
+        let mut glacier = Glacier::new();
+        glacier.compile("def g(y) fn() y end end").unwrap();
+        assert!(glacier.vm.verify().is_ok());
+        function(&mut glacier, "anonymous").upvalues = vec![(true, 4)];
+        assert_eq!(
+            glacier.vm.verify().unwrap_err().kind,
+            VerifyErrorKind::LocalOutOfRange(4)
+        );
+        function(&mut glacier, "anonymous").upvalues = vec![(false, 5)];
+        assert_eq!(
+            glacier.vm.verify().unwrap_err().kind,
+            VerifyErrorKind::UpvalueOutOfRange(5)
+        );
+        // A function without upvalues is loaded as a constant, so it gets none
+        function(&mut glacier, "g").upvalues = vec![(true, 0)];
+        assert!(matches!(
+            glacier.vm.verify().unwrap_err().kind,
+            VerifyErrorKind::NotAClosure(_)
+        ));
+
+        // A function cannot jump into the top level, where locals are checked against the
+        // globals instead of its own
+        let mut compiler = Glacier::new();
+        compiler
+            .compile("a = 1; b = 2; c = 3; def f() 1 end; f(); c")
+            .unwrap();
+        let code = &compiler.vm.bytecodes;
+        let target = (0..code.len() - 1)
+            .rev()
+            .find(|&i| code[i] == LOAD_LOCAL && code[i + 1] == 2)
+            .unwrap();
+        let address = function(&mut compiler, "f").address;
+        compiler.vm.bytecodes[address] = JUMP;
+        compiler.vm.bytecodes[address + 1] = target as Byte;
+        assert_eq!(
+            compiler.vm.verify().unwrap_err().kind,
+            VerifyErrorKind::OtherRegion(target)
+        );
+        let data = compiler.vm.save_bytecode();
+        assert!(Glacier::new().vm.load_bytecode(&data).is_err());
+
+        // Assigning to an element used to leave the value on the stack
+        let mut glacier = Glacier::new();
+        glacier.eval("a = [0]; for i in 1000: a[0] = i").unwrap();
+        assert!(glacier.vm.stack.len() < 10);
+    }
+
+    #[test]
+    fn test_wide_operands() {
+        use crate::ast::{Expression, Statement};
+
+        // More code, constants and variables than 16-bit operands could address. Parsing that
+        // much is slow in debug builds, so the assignments are copied into a small program
+        let n = 70000;
+        let template = "total = 0.0\n\
+                        for round in 2\n\
+                        v = 0.5\n\
+                        total = total + v\n\
+                        end\n\
+                        def last(x) x + total end\n\
+                        [total, last(0.5)]";
+        let mut names: Vec<String> = (0..n).map(|i| format!("v{i}")).collect();
+        names[n - 1] = "v".to_string();
+        let values: Vec<String> = (0..n).map(|i| format!("{i}.5")).collect();
+
+        let mut program = crate::parser::parse(template).unwrap();
+        let body = match &mut program[1] {
+            Statement::ExprStmt(e) => match &mut e.expr {
+                Expression::For(f) => &mut f.body,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        let assign = body.remove(0);
+        let copies = (0..n).map(|i| {
+            let mut copy = assign.clone();
+            if let Statement::ExprStmt(e) = &mut copy {
+                if let Expression::SetVar(set) = &mut e.expr {
+                    set.name = &names[i];
+                    if let Expression::Float(f) = &mut set.value {
+                        f.value = &values[i];
+                    }
+                }
+            }
+            copy
+        });
+        body.splice(0..0, copies);
+
+        let mut glacier = Glacier::with_options(VMOptions::default().stack_size(1 << 20));
+        glacier.vm.set_source(template.to_string());
+        glacier.vm.compile(&program);
+        assert!(glacier.vm.error.is_none());
+        glacier.vm.optimize();
+        glacier.vm.verify().unwrap();
+
+        assert!(glacier.vm.bytecodes.len() > 1 << 16);
+        assert!(glacier.vm.constants.len() > 1 << 16);
+        assert!(glacier.vm.global_slots() > 1 << 16);
+        assert_eq!(
+            glacier.run().unwrap().debug_format(),
+            "[139999.0, 139999.5]"
+        );
+
+        let mut loaded = Glacier::with_options(VMOptions::default().stack_size(1 << 20));
+        loaded
+            .vm
+            .load_bytecode(&glacier.vm.save_bytecode())
+            .unwrap();
+        assert_eq!(loaded.run().unwrap().debug_format(), "[139999.0, 139999.5]");
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        use crate::ast::{Expression, Statement};
+
+        // Deeper than scopes used to be limited to, built from the AST as deep nesting is
+        // slow to parse. Compiling recurses once per level, hence the larger stack
+        let nested = || {
+            let template = "x = 0\nfor i in 1\nx = x + 1\nend\nx";
+            let mut program = crate::parser::parse(template).unwrap();
+            for _ in 0..600 {
+                let mut outer = program[1].clone();
+                if let Statement::ExprStmt(e) = &mut outer {
+                    if let Expression::For(f) = &mut e.expr {
+                        f.body = vec![program[1].clone()];
+                    }
+                }
+                program[1] = outer;
+            }
+
+            let mut glacier = Glacier::new();
+            glacier.vm.set_source(template.to_string());
+            glacier.vm.compile(&program);
+            assert!(glacier.vm.error.is_none());
+            glacier.vm.optimize();
+            glacier.vm.verify().unwrap();
+            glacier.run().unwrap().debug_format()
+        };
+        let result = std::thread::Builder::new()
+            .stack_size(256 << 20)
+            .spawn(nested)
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(result, "1");
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let compiled = |source: &str| {
+            let mut glacier = Glacier::new();
+            glacier.compile(source).unwrap();
+            (
+                glacier.vm.disassemble(),
+                glacier.run().map(|v| v.debug_format()),
+            )
+        };
+        let ok = |s: &str| Ok(s.to_string());
+
+        let (code, result) = compiled("-(2 * 3 + 1) / 2.0 + 2 ** 2");
+        assert_eq!(code, "0000: LOAD_CONST 0003 (0.5)\n0002: POP_LAST \n");
+        assert_eq!(result, ok("0.5"));
+        assert_eq!(compiled("\"a\" + \"b\" == \"ab\" && !0").1, ok("true"));
+        assert_eq!(
+            compiled("[1 < 2, 1 >= 2.5, 7 % -3]").1,
+            ok("[true, false, -2]")
+        );
+        assert_eq!(compiled("x = 4\n(1 < 2) && x").1, ok("4"));
+
+        // Errors stay runtime errors, raised where the operation is
+        for (source, op) in [
+            ("x = 1\nx = 1 / 0", "BINARY_DIV"),
+            ("x = 1\nx = 1 + \"a\"", "BINARY_ADD"),
+        ] {
+            let (code, result) = compiled(source);
+            assert!(code.contains(op));
+            match result {
+                Err(GlacierError::Runtime(info)) => assert_eq!(info.line, 2),
+                e => panic!("Expected a runtime error, got {e:?}"),
+            }
+        }
+
+        // Constant conditions keep only the branch taken
+        let (code, result) = compiled("x = if 1 > 2: 1 else: 2\nx * 3");
+        assert!(!code.contains("JUMP"));
+        assert_eq!(result, ok("6"));
+        let (code, result) =
+            compiled("i = 0\nwhile true\n    i = i + 1\n    if i == 3: break\nend\ni");
+        // Only the if tests a condition
+        let tests =
+            code.matches("JUMP_IF_FALSE").count() + code.matches("COMPARE_LOCAL_JUMP").count();
+        assert_eq!(tests, 1);
+        assert_eq!(result, ok("3"));
+        let (code, result) = compiled("while false\n    def f() 1 end\n    break\nend\n5");
+        assert!(!code.contains("JUMP"));
+        assert_eq!(result, ok("5"));
+
+        // Dropped branches are still checked
+        let mut glacier = Glacier::new();
+        assert!(matches!(
+            glacier.eval("if false: undefined"),
+            Err(GlacierError::Compile(_))
+        ));
+        assert!(matches!(
+            glacier.eval("while 0: break\nbreak"),
+            Err(GlacierError::Compile(_))
+        ));
+    }
+
+    #[test]
+    fn test_compaction() {
+        use crate::vm::bytecode::*;
+
+        let mut glacier = Glacier::new();
+        glacier.eval("def twice(f, x) f(f(x)) end").unwrap();
+        let entry = glacier.vm.bytecodes.len();
+        glacier
+            .compile(
+                "total = 0\n\
+                 for i in 1..10\n\
+                 \x20   total\n\
+                 \x20   if i % 2 == 0: next\n\
+                 \x20   total = total + twice(fn(x) x + i end, 0)\n\
+                 end\n\
+                 total\n\
+                 total + \"a\"",
+            )
+            .unwrap();
+
+        let code = &glacier.vm.bytecodes;
+        assert_eq!(glacier.vm.lines.len(), code.len());
+        let mut i = entry;
+        while i < code.len() {
+            assert_ne!(code[i], NOOP, "NOOP left at {i:04x}");
+            i += 1 + operands(code[i]);
+        }
+
+        // Jumps and the closure still find their code, and errors their line
+        match glacier.run() {
+            // Lines count from the start of the session
+            Err(GlacierError::Runtime(info)) => {
+                assert_eq!(info.line, 9);
+                assert_eq!(info.message, "Unsupported Binary operation: int + string");
+            }
+            e => panic!("Expected a runtime error, got {e:?}"),
+        }
+        assert_eq!(glacier.eval("total").unwrap().debug_format(), "50");
+        assert_eq!(
+            glacier
+                .eval("twice(fn(x) x * 3 end, 1)")
+                .unwrap()
+                .debug_format(),
+            "9"
+        );
+    }
+
+    #[test]
+    fn test_superinstructions() {
+        let run = |source: &str, fuse: bool| {
+            let mut glacier = Glacier::with_options(VMOptions::default().superinstructions(fuse));
+            let result = glacier
+                .eval(source)
+                .map(|v| v.debug_format())
+                .map_err(|e| e.render(glacier.source()));
+            (glacier.vm.disassemble(), result)
+        };
+
+        for (source, fused) in [
+            (
+                "i = 0\nwhile i < 10\n    i = i + 1\nend\ni",
+                &["INCREMENT_LOCAL", "COMPARE_LOCAL_JUMP"][..],
+            ),
+            (
+                "s = \"a\"\nwhile s != \"aaa\"\n    s = s + \"a\"\nend\ns",
+                &["INCREMENT_LOCAL", "COMPARE_LOCAL_JUMP"],
+            ),
+            (
+                "x = 0.5\ny = 2\nz = x + y\nif y >= 2.0: z = z + 1\nz",
+                &["ADD_LOCALS", "COMPARE_LOCAL_JUMP", "INCREMENT_LOCAL"],
+            ),
+            // Within a call, relative to its frame
+            (
+                "def count(n)\n    i = 0\n    while i < 5\n        i = i + 1\n    end\n    i + n\nend\ncount(3)",
+                &["INCREMENT_LOCAL", "COMPARE_LOCAL_JUMP", "ADD_LOCALS"],
+            ),
+            // NaN fails every comparison but !=
+            (
+                "n = (-1.0) ** 0.5\nr = 0\nif n < 1: r = r + 1\nif n >= 1: r = r + 10\nif n != 1: r = r + 100\nr",
+                &["COMPARE_LOCAL_JUMP"],
+            ),
+            ("s = \"a\"\ns = s + 1", &["INCREMENT_LOCAL"]),
+            ("s = \"a\"\nif s < 1: s", &["COMPARE_LOCAL_JUMP"]),
+            ("s = [1]\nt = 1\ns + t", &["ADD_LOCALS"]),
+            // The && jumps to the load of b, so the addition is kept apart
+            ("c = 0\na = 1\nb = 2\nr = (c && a) + b\nc = 1\n[r, (c && a) + b]", &[]),
+        ] {
+            let (code, result) = run(source, true);
+            let (plain, expected) = run(source, false);
+            for name in fused {
+                assert!(code.contains(name), "{name} missing from\n{code}");
+                assert!(!plain.contains(name));
+            }
+            assert_eq!(fused.is_empty(), code == plain, "{code}");
+            assert_eq!(result, expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn test_gc_stats() {
+        let mut glacier = Glacier::with_options(VMOptions::default().gc_threshold(100));
+        let before = glacier.vm.gc_stats();
+        glacier
+            .eval("for i in 1000; junk = [i, \"#{i}\"]; end")
+            .unwrap();
+
+        let stats = glacier.vm.gc_stats();
+        assert!(stats.collections > before.collections + 5, "{stats}");
+        assert!(stats.allocated - before.allocated >= 3000, "{stats}");
+        assert_eq!(stats.allocated - stats.swept, stats.live);
+        assert_eq!(stats.live, glacier.vm.heap.live());
+        assert!(stats.bytes_allocated > stats.allocated);
+        assert!(stats.pause > before.pause);
+        assert!(stats.to_string().starts_with("Collections: "));
+    }
+
+    #[test]
+    fn test_sandboxing() {
+        let mut glacier = Glacier::with_options(VMOptions::default().max_instructions(1000));
+        let e = glacier
+            .eval("x = 1\nwhile true; x = x + 1; end")
+            .unwrap_err();
+        assert!(matches!(e, GlacierError::BudgetExhausted(_)), "{e}");
+        assert_eq!(e.info().message, "Instruction budget of 1000 exhausted");
+        assert_eq!(e.info().line, 2);
+
+        // The budget is per eval, and runs that fit in it are not affected
+        assert!(glacier.eval("x > 10").unwrap().is_equal(&Value::Bool(true)));
+        assert!(glacier
+            .eval("s = 0; for i in 10: s = s + i; s")
+            .unwrap()
+            .is_equal(&Value::Int(45)));
+
+        let mut glacier = Glacier::new();
+        let handle = glacier.interrupt_handle();
+        let setter = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            handle.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+        let e = glacier
+            .eval("def spin() while true end end\nspin()")
+            .unwrap_err();
+        setter.join().unwrap();
+        assert!(matches!(e, GlacierError::Interrupted(_)), "{e}");
+        assert!(glacier
+            .eval("spin == spin && 1 + 1")
+            .unwrap()
+            .is_equal(&Value::Int(2)));
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut glacier = Glacier::with_options(VMOptions::default().max_heap(5000));
+        glacier
+            .eval("def build(n) a = []; for i in n: a = [a, [i]]; a end")
+            .unwrap();
+        let e = glacier.eval("x = build(100000)").unwrap_err();
+        assert!(matches!(e, GlacierError::Runtime(_)));
+        assert_eq!(e.info().message, "Out of memory: heap limit of 5000 values");
+
+        // Garbage does not count against the limit, and the VM is usable after running out
+        assert!(glacier
+            .eval("for i in 100000: x = [i]; x[0]")
+            .unwrap()
+            .is_equal(&Value::Int(99999)));
+        assert_eq!(
+            glacier.eval("build(100)[1]").unwrap().debug_format(),
+            "[99]"
+        );
+
+        let mut glacier = Glacier::with_options(VMOptions::default().max_frames(50));
+        glacier
+            .eval("def f(n) if n == 0: return 0; f(n - 1) + 1 end")
+            .unwrap();
+        assert!(glacier.eval("f(40)").unwrap().is_equal(&Value::Int(40)));
+        assert_eq!(
+            glacier.eval("f(60)").unwrap_err().info().message,
+            "Stack overflow"
+        );
+
+        // Superinstructions that push check the stack like LOAD_CONST
+        let mut glacier = Glacier::with_options(VMOptions::default().stack_size(5));
+        assert_eq!(
+            glacier
+                .eval("def f(a, b) (a + b) + (a + b) end; f(1, 2)")
+                .unwrap_err()
+                .info()
+                .message,
+            "Stack overflow"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "was freed")]
+    fn test_gc_verify_freed() {
+        let mut glacier = Glacier::new();
+        let handle = glacier.root(&Value::String("gone".to_string()));
+        let ptr = glacier.vm.get_root(handle);
+        glacier.unroot(handle);
+        glacier.vm.gc_recollect();
+        glacier.vm.heap.verify(&[ptr]);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not in the heap")]
+    fn test_gc_verify_outside() {
+        let glacier = Glacier::new();
+        let mut outside = Value::Null;
+        glacier.vm.heap.verify(&[&mut outside as *mut Value]);
     }
 }
@@ -1,4 +1,5 @@
-pub type Byte = u16;
+// Opcodes and their operands, wide enough for any address, constant index or local slot
+pub type Byte = u32;
 // Size doesn't really matter
 pub type ByteCodes = Vec<Byte>;
 
@@ -51,15 +52,50 @@ bytecodes! {
     LOAD_CONST;
 
     // LOAD_LOCAL address
-    // Stack: stack[address]=value -> [value]
-    // Loads stack[address] onto stack
+    // Stack: stack[base+address]=value -> [value]
+    // Loads stack[base+address] onto stack, relative to the current call frame
     LOAD_LOCAL;
 
+    // LOAD_GLOBAL address
+    // Stack: stack[address]=value -> [value]
+    // Loads stack[address] onto stack, ignoring the current call frame
+    LOAD_GLOBAL;
+
+    // REPLACE_GLOBAL address
+    // Stack: [addr, ..., a] -> [a, ...]
+    // Pops a and puts its shallow copy at absolute address
+    REPLACE_GLOBAL;
+
+    // LOAD_UPVALUE index
+    // Stack: [] -> [value]
+    // Loads the current closure's upvalue[index] onto stack
+    LOAD_UPVALUE;
+
+    // REPLACE_UPVALUE index
+    // Stack: [a] -> []
+    // Pops a and puts its shallow copy in the current closure's upvalue[index]
+    REPLACE_UPVALUE;
+
     // MAKE_ARRAY length
     // Stack: [d, c, b, a] -> [ARR]
     // Pops `length` items and pushes array
     MAKE_ARRAY;
 
+    // MAKE_STRING length
+    // Stack: [a, b, c] -> ["abc"]
+    // Pops `length` items and pushes the concatenation of their print formats
+    MAKE_STRING;
+
+    // MAKE_RANGE inclusive
+    // Stack: [a, b] -> [a..b]
+    // Makes a range from a to b, including b if inclusive is 1
+    MAKE_RANGE;
+
+    // MAKE_HASH length
+    // Stack: [k1, v1, ..., kn, vn] -> [HASH]
+    // Pops `length` key-value pairs and pushes hash
+    MAKE_HASH;
+
     // DEBUG_PRINT
     // Stack: [a] -> []
     // Debug prints a
@@ -70,6 +106,16 @@ bytecodes! {
     // Prints a
     ECHO_PRINT;
 
+    // ITER_START address
+    // Stack: [a] -> []
+    // Pops a into local[address] and starts a new iteration state in local[address+1]
+    ITER_START;
+
+    // FOR_ITER address target
+    // Stack: [] -> [item]
+    // Pushes the next item of the iteration in local[address], or jumps to target when done
+    FOR_ITER;
+
     // JUMP_IF_FALSE address
     // Stack: [a] -> []
     // Jumps to address if stack if falsy
@@ -90,6 +136,26 @@ bytecodes! {
     // Pushes c = a[b]
     GET;
 
+    // GET_OR_INSERT
+    // Stack: [a, b] -> [c]
+    // Pushes c = a[b] like GET, but inserts a missing hash key as null so it can be assigned
+    GET_OR_INSERT;
+
+    // CALL argc
+    // Stack: [f, a1, ..., an] -> [f, a1, ..., an, locals...]
+    // Calls f with argc arguments, pushing a new call frame
+    CALL;
+
+    // MAKE_CLOSURE address
+    // Stack: [] -> [closure]
+    // Makes a closure of the function at const[address], capturing its upvalues
+    MAKE_CLOSURE;
+
+    // RETURN
+    // Stack: [f, a1, ..., an, locals..., a] -> [a]
+    // Pops the current call frame and pushes its result a
+    RETURN;
+
     // UNARY_NEG
     // Stack: [a] -> [-a]
     // Negates a
@@ -149,11 +215,33 @@ bytecodes! {
     // Stack: [a, b] -> [a >= b]
     // a >= b
     BINARY_GE;
+
+    // Superinstructions, which optimize puts in place of common sequences
+
+    // INCREMENT_LOCAL address constant
+    // Stack: [] -> []
+    // Adds const[constant] to local[address], like LOAD_LOCAL, LOAD_CONST, BINARY_ADD, REPLACE
+    INCREMENT_LOCAL;
+
+    // COMPARE_LOCAL_JUMP address constant comparison target
+    // Stack: [] -> []
+    // Jumps to target unless local[address] <comparison> const[constant], comparison being one
+    // of the BINARY_ comparisons, like LOAD_LOCAL, LOAD_CONST, comparison, JUMP_IF_FALSE
+    COMPARE_LOCAL_JUMP;
+
+    // ADD_LOCALS a b
+    // Stack: [] -> [local[a] + local[b]]
+    // Like LOAD_LOCAL a, LOAD_LOCAL b, BINARY_ADD
+    ADD_LOCALS;
 }
 
 pub fn operands(bytecode: Byte) -> usize {
     match bytecode {
-        LOAD_CONST | LOAD_LOCAL | REPLACE | JUMP_IF_FALSE | JUMP_IF_FALSE_NO_POP | JUMP => 1,
+        LOAD_CONST | LOAD_LOCAL | LOAD_GLOBAL | LOAD_UPVALUE | REPLACE | REPLACE_GLOBAL
+        | REPLACE_UPVALUE | MAKE_ARRAY | MAKE_STRING | MAKE_HASH | MAKE_RANGE | MAKE_CLOSURE
+        | JUMP_IF_FALSE | JUMP_IF_FALSE_NO_POP | JUMP | CALL | ITER_START => 1,
+        FOR_ITER | INCREMENT_LOCAL | ADD_LOCALS => 2,
+        COMPARE_LOCAL_JUMP => 4,
         _ => 0,
     }
 }
@@ -166,12 +254,26 @@ pub fn bytecode_name(bytecode: Byte) -> &'static str {
         SET_IN_PLACE => "SET_IN_PLACE",
         LOAD_CONST => "LOAD_CONST",
         LOAD_LOCAL => "LOAD_LOCAL",
+        LOAD_GLOBAL => "LOAD_GLOBAL",
+        REPLACE_GLOBAL => "REPLACE_GLOBAL",
+        LOAD_UPVALUE => "LOAD_UPVALUE",
+        REPLACE_UPVALUE => "REPLACE_UPVALUE",
+        MAKE_CLOSURE => "MAKE_CLOSURE",
         MAKE_ARRAY => "MAKE_ARRAY",
+        MAKE_STRING => "MAKE_STRING",
+        MAKE_HASH => "MAKE_HASH",
+        MAKE_RANGE => "MAKE_RANGE",
+        ITER_START => "ITER_START",
+        FOR_ITER => "FOR_ITER",
         JUMP_IF_FALSE => "JUMP_IF_FALSE",
         JUMP_IF_FALSE_NO_POP => "JUMP_IF_FALSE_NO_POP",
         JUMP => "JUMP",
         DEBUG_PRINT => "DEBUG_PRINT",
+        ECHO_PRINT => "ECHO_PRINT",
         GET => "GET",
+        GET_OR_INSERT => "GET_OR_INSERT",
+        CALL => "CALL",
+        RETURN => "RETURN",
         UNARY_NEG => "UNARY_NEG",
         UNARY_NOT => "UNARY_NOT",
         BINARY_ADD => "BINARY_ADD",
@@ -179,12 +281,16 @@ pub fn bytecode_name(bytecode: Byte) -> &'static str {
         BINARY_MUL => "BINARY_MUL",
         BINARY_DIV => "BINARY_DIV",
         BINARY_MOD => "BINARY_MOD",
+        BINARY_EXP => "BINARY_EXP",
         BINARY_EQ => "BINARY_EQ",
         BINARY_NE => "BINARY_NE",
         BINARY_LT => "BINARY_LT",
         BINARY_LE => "BINARY_LE",
         BINARY_GT => "BINARY_GT",
         BINARY_GE => "BINARY_GE",
+        INCREMENT_LOCAL => "INCREMENT_LOCAL",
+        COMPARE_LOCAL_JUMP => "COMPARE_LOCAL_JUMP",
+        ADD_LOCALS => "ADD_LOCALS",
         _ => "UNKNOWN",
     }
 }
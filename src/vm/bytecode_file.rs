@@ -0,0 +1,258 @@
+// Compiled programs saved as .glcb files, so that they can run without parsing and compiling
+//
+// All numbers are little endian. The file starts with the magic bytes, the format version
+// as a u16 and a FNV-1a checksum of the rest as a u32, followed by the entry address,
+// the number of global slots, the source, the bytecodes with their spans and the constants.
+
+use std::rc::Rc;
+
+use crate::ast::AstSpan;
+use crate::value::{Function, Value};
+use crate::vm_bc::VM;
+
+use super::bytecode::Byte;
+
+pub const MAGIC: &[u8; 4] = b"GLCB";
+// Bumped whenever the layout or the meaning of bytecodes changes
+pub const FORMAT_VERSION: u16 = 3;
+
+const HEADER_SIZE: usize = MAGIC.len() + 2 + 4;
+
+const TAG_FLOAT: u8 = 0;
+const TAG_INT: u8 = 1;
+const TAG_STRING: u8 = 2;
+const TAG_BOOL: u8 = 3;
+const TAG_NULL: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+pub fn is_bytecode_file(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in data {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn usize(&mut self, v: usize) {
+        self.u64(v as u64);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.usize(s.len());
+        self.data.extend_from_slice(s.as_bytes());
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < n {
+            return Err("Bytecode file is truncated".to_string());
+        }
+        self.pos += n;
+        Ok(&self.data[self.pos - n..self.pos])
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, String> {
+        usize::try_from(self.u64()?).map_err(|_| "Bytecode file is corrupted".to_string())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.usize()?;
+        String::from_utf8(self.bytes(len)?.to_vec())
+            .map_err(|_| "Bytecode file is corrupted".to_string())
+    }
+}
+
+impl VM {
+    // Everything needed to run the last compiled program again, see load_bytecode
+    pub fn save_bytecode(&self) -> Vec<u8> {
+        let mut w = Writer { data: Vec::new() };
+        w.usize(self.entry);
+        w.usize(self.global_slots());
+        w.str(&self.source);
+
+        w.usize(self.bytecodes.len());
+        for (bytecode, span) in self.bytecodes.iter().zip(self.lines.iter()) {
+            w.u32(*bytecode);
+            w.usize(span.start);
+            w.usize(span.end);
+        }
+
+        w.usize(self.constants.len());
+        for constant in self.constants.iter() {
+            match constant {
+                Value::Float(f) => {
+                    w.u8(TAG_FLOAT);
+                    w.data.extend_from_slice(&f.to_le_bytes());
+                }
+                Value::Int(i) => {
+                    w.u8(TAG_INT);
+                    w.data.extend_from_slice(&i.to_le_bytes());
+                }
+                Value::String(s) => {
+                    w.u8(TAG_STRING);
+                    w.str(s);
+                }
+                Value::Bool(b) => {
+                    w.u8(TAG_BOOL);
+                    w.u8(*b as u8);
+                }
+                Value::Null => w.u8(TAG_NULL),
+                Value::Function(f) => {
+                    w.u8(TAG_FUNCTION);
+                    w.str(&f.name);
+                    w.usize(f.arity);
+                    w.usize(f.address);
+                    w.usize(f.locals);
+                    w.usize(f.upvalues.len());
+                    for (is_local, index) in f.upvalues.iter() {
+                        w.u8(*is_local as u8);
+                        w.usize(*index);
+                    }
+                }
+                x => unreachable!("{} is never a constant", x.type_name()),
+            }
+        }
+
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        data.extend_from_slice(&checksum(&w.data).to_le_bytes());
+        data.extend_from_slice(&w.data);
+        data
+    }
+
+    // Replaces the program with one saved by save_bytecode, ready to execute once verified.
+    // Meant for a VM that has not compiled anything nor registered natives
+    pub fn load_bytecode(&mut self, data: &[u8]) -> Result<(), String> {
+        if !is_bytecode_file(data) {
+            return Err("Not a Glacier bytecode file".to_string());
+        }
+        let mut r = Reader { data, pos: 0 };
+        r.bytes(MAGIC.len())?;
+        let version = r.u16()?;
+        if version != FORMAT_VERSION {
+            return Err(format!(
+                "Bytecode file has format version {version}, expected {FORMAT_VERSION}"
+            ));
+        }
+        let expected = r.u32()?;
+        debug_assert_eq!(r.pos, HEADER_SIZE);
+        if checksum(&data[HEADER_SIZE..]) != expected {
+            return Err("Bytecode file is corrupted: checksum mismatch".to_string());
+        }
+
+        let entry = r.usize()?;
+        let globals = r.usize()?;
+        // Reserved on the stack before anything runs
+        if globals > self.options.stack_size {
+            return Err(format!(
+                "Bytecode file needs {globals} global slots, more than the stack size of {}",
+                self.options.stack_size
+            ));
+        }
+        let source = r.string()?;
+
+        let count = r.usize()?;
+        let mut bytecodes = Vec::new();
+        let mut lines = Vec::new();
+        for _ in 0..count {
+            bytecodes.push(r.u32()?);
+            let start = r.usize()?;
+            let end = r.usize()?;
+            lines.push(AstSpan { start, end });
+        }
+
+        let count = r.usize()?;
+        let mut constants = Vec::new();
+        for _ in 0..count {
+            constants.push(match r.u8()? {
+                TAG_FLOAT => Value::Float(f64::from_le_bytes(r.bytes(8)?.try_into().unwrap())),
+                TAG_INT => Value::Int(i64::from_le_bytes(r.bytes(8)?.try_into().unwrap())),
+                TAG_STRING => Value::String(r.string()?),
+                TAG_BOOL => Value::Bool(r.u8()? != 0),
+                TAG_NULL => Value::Null,
+                TAG_FUNCTION => {
+                    let name = r.string()?;
+                    let arity = r.usize()?;
+                    let address = r.usize()?;
+                    let locals = r.usize()?;
+                    let mut upvalues = Vec::new();
+                    for _ in 0..r.usize()? {
+                        upvalues.push((r.u8()? != 0, r.usize()?));
+                    }
+                    Value::Function(Rc::new(Function {
+                        name,
+                        arity,
+                        address,
+                        locals,
+                        upvalues,
+                    }))
+                }
+                tag => return Err(format!("Bytecode file has unknown constant tag {tag}")),
+            });
+        }
+
+        if r.pos != data.len() || entry > bytecodes.len() {
+            return Err("Bytecode file is corrupted".to_string());
+        }
+
+        self.set_source(source);
+        self.bytecodes = bytecodes;
+        self.lines = lines;
+        self.entry = entry;
+        self.current_compiler.count = globals;
+        self.current_compiler.max_count = globals;
+        self.constants.clear();
+        self.constant_hash_int.clear();
+        for (i, constant) in constants.into_iter().enumerate() {
+            if let Value::Int(v) = constant {
+                self.constant_hash_int.insert(v, i as Byte);
+            }
+            self.constants.push(constant);
+        }
+        self.verify().map_err(|e| e.to_string())
+    }
+}
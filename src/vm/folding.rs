@@ -0,0 +1,109 @@
+// Constant folding: operators applied to literals are evaluated while compiling, with the
+// same Value methods execute uses, so that `2 * 3` compiles to the constant 6.
+//
+// Anything that would fail at runtime, like a division by zero, an unsupported operand type
+// or an integer overflow, is left for execute so that it still fails there.
+
+use crate::ast::*;
+use crate::value::{BinOpResult, Value};
+use crate::vm_bc::*;
+
+use super::bytecode::*;
+
+impl VM {
+    // Value of an expression made only of literals and operators,
+    // None if it has to be computed at runtime
+    pub fn constant_value(&self, expression: &Expression) -> Option<Value> {
+        match expression {
+            Expression::Int(num) => num.value.parse::<i64>().ok().map(Value::Int),
+            Expression::Float(num) => num.value.parse::<f64>().ok().map(Value::Float),
+            Expression::String_(s) => Some(Value::String(s.value.to_string())),
+            Expression::Bool(b) => Some(Value::Bool(b.value)),
+
+            Expression::Prefix(prefix) => {
+                match (prefix.operator, self.constant_value(&prefix.right)?) {
+                    ("-", Value::Int(i)) => Some(Value::Int(i.saturating_neg())),
+                    ("-", Value::Float(f)) => Some(Value::Float(-f)),
+                    ("!", value) => Some(Value::Bool(!value.is_truthy())),
+                    _ => None,
+                }
+            }
+
+            Expression::Infix(infix) => {
+                let left = self.constant_value(&infix.left)?;
+                // Short circuits, so the right side only needs to be constant when it is used
+                match infix.operator {
+                    "&&" if !left.is_truthy() => return Some(left),
+                    "||" if left.is_truthy() => return Some(left),
+                    "&&" | "||" => return self.constant_value(&infix.right),
+                    _ => (),
+                }
+                let right = self.constant_value(&infix.right)?;
+                fold_binary(infix.operator, &left, &right)
+            }
+
+            _ => None,
+        }
+    }
+
+    // Loads a constant, sharing the slots of booleans and integers
+    pub fn load_constant(&mut self, value: Value, pos: AstSpan) {
+        let index = match value {
+            Value::Bool(false) => BOOL_FALSE_CONSTANT as Byte,
+            Value::Bool(true) => BOOL_TRUE_CONSTANT as Byte,
+            Value::Null => NULL_CONSTANT as Byte,
+            Value::Int(i) => match self.constant_hash_int.get(&i) {
+                Some(k) => *k,
+                None => {
+                    self.constants.push(value);
+                    let index = self.constants.len() as Byte - 1;
+                    self.constant_hash_int.insert(i, index);
+                    index
+                }
+            },
+            _ => {
+                self.constants.push(value);
+                self.constants.len() as Byte - 1
+            }
+        };
+        self.push_bytecode(LOAD_CONST, pos);
+        self.push_bytecode(index, pos);
+    }
+}
+
+fn fold_binary(operator: &str, left: &Value, right: &Value) -> Option<Value> {
+    // Overflows panic or wrap in execute, so they happen there
+    if let (Value::Int(a), Value::Int(b)) = (left, right) {
+        let checked = match operator {
+            "+" => a.checked_add(*b),
+            "-" => a.checked_sub(*b),
+            "*" => a.checked_mul(*b),
+            "/" => a.checked_div(*b),
+            "%" => a.checked_rem(*b).and_then(|r| r.checked_add(*b)),
+            _ => Some(0),
+        };
+        checked?;
+    }
+
+    let result = match operator {
+        "+" => left.binary_add(right),
+        "-" => left.binary_sub(right),
+        "*" => left.binary_mul(right),
+        "/" => left.binary_div(right),
+        "%" => left.binary_mod(right),
+        "**" => left.binary_exp(right),
+        "<" => left.binary_lt(right),
+        "<=" => left.binary_le(right),
+        ">" => left.binary_gt(right),
+        ">=" => left.binary_ge(right),
+        "==" => return Some(Value::Bool(left.is_equal(right))),
+        "!=" => return Some(Value::Bool(!left.is_equal(right))),
+        // Ranges are not constants
+        _ => return None,
+    };
+    match result {
+        // Left for the collector, nothing refers to it
+        BinOpResult::Ok(value) => Some(unsafe { &*value }.clone()),
+        BinOpResult::Error(_) | BinOpResult::NoMatch => None,
+    }
+}
@@ -1,12 +1,18 @@
-use std::alloc::{alloc, dealloc, Layout};
-use std::collections::hash_map::HashMap;
-use std::sync::Mutex;
-
-use lazy_static::lazy_static;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::rc::Rc;
+use std::time::Duration;
 
 use crate::vm::value::Value;
 
-static LAYOUT: Layout = Layout::new::<Value>();
+// Cells added to the heap each time it runs out of free ones
+pub const CHUNK_SIZE: usize = 1024;
+
+// Values allocated since the last cycle before a new one starts
+pub const GC_THRESHOLD: usize = 1 << 16;
+// Values marked or cells swept by each incremental step
+pub const GC_STEP_WORK: usize = 1024;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
@@ -14,91 +20,390 @@ pub enum GCItemState {
     White,
     Grey,
     Black,
+    // Never collected
     Persistent,
+    // Holds no value, waiting in the free list
+    Free,
 }
 
-lazy_static! {
-    pub static ref ALL_ALLOCATIONS: Mutex<HashMap<usize, GCItemState>> = Mutex::new(HashMap::new());
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GCPhase {
+    Idle,
+    Mark,
+    Sweep,
 }
 
-pub static mut LAST_ALLOCATED: usize = 0;
+// Counters since the heap was created, see VM::gc_stats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GCStats {
+    // Finished collection cycles
+    pub collections: usize,
+    pub allocated: usize,
+    pub bytes_allocated: usize,
+    pub swept: usize,
+    pub live: usize,
+    // Memory reserved for cells, used or not
+    pub heap_bytes: usize,
+    // Time spent collecting, summed over every step
+    pub pause: Duration,
+}
 
-pub const GC_FORCE_COLLECT: usize = 1 << 19;
+impl fmt::Display for GCStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Collections: {}", self.collections)?;
+        writeln!(
+            f,
+            "Allocated:   {} values, {} bytes",
+            self.allocated, self.bytes_allocated
+        )?;
+        writeln!(f, "Swept:       {} values", self.swept)?;
+        writeln!(
+            f,
+            "Live:        {} values, heap of {} bytes",
+            self.live, self.heap_bytes
+        )?;
+        write!(f, "Pause:       {:?}", self.pause)
+    }
+}
 
-pub fn alloc_value_ptr() -> *mut Value {
-    let ptr = unsafe { alloc(LAYOUT) as *mut Value };
-    ALL_ALLOCATIONS
-        .lock()
-        .unwrap()
-        .insert(ptr as usize, GCItemState::White);
-    ptr
+// The value comes first, so a pointer to it is also a pointer to its cell
+#[repr(C)]
+pub struct HeapCell {
+    value: UnsafeCell<MaybeUninit<Value>>,
+    state: Cell<GCItemState>,
 }
 
-pub fn alloc_new_value(val: Value) -> *mut Value {
-    let ptr = alloc_value_ptr();
-    unsafe {
-        *ptr = val;
+// Values of one VM, freed when it is dropped
+//
+// Collection is incremental: a cycle marks from the roots a few values at a time,
+// then sweeps a few cells at a time, while the program keeps running in between.
+// White and Black swap roles after every sweep, so survivors never have to be
+// whitened again. Values allocated during a cycle get the marked color, and the
+// write barriers keep marked values from pointing to unmarked ones.
+pub struct Heap {
+    // Chunks are never resized, so cells keep their address
+    chunks: RefCell<Vec<Box<[HeapCell]>>>,
+    free: RefCell<Vec<*const HeapCell>>,
+    live: Cell<usize>,
+    live_after_collect: Cell<usize>,
+
+    phase: Cell<GCPhase>,
+    // Color of values not marked yet in this cycle, the other one of White and Black marks them
+    white: Cell<GCItemState>,
+    // Values to trace, with the index of the first child not traced yet
+    grey: RefCell<Vec<(*mut Value, usize)>>,
+    // Index of the next cell to sweep, counting across chunks
+    sweep_cursor: Cell<usize>,
+
+    collections: Cell<usize>,
+    allocated: Cell<usize>,
+    swept: Cell<usize>,
+}
+
+thread_local! {
+    // Heap that alloc_new_value allocates in, see Heap::enter
+    static CURRENT_HEAP: RefCell<Option<Rc<Heap>>> = const { RefCell::new(None) };
+}
+
+// Makes the previously entered heap current again when dropped
+pub struct HeapGuard {
+    previous: Option<Rc<Heap>>,
+}
+
+impl Drop for HeapGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT_HEAP.with(|current| *current.borrow_mut() = previous);
     }
-    ptr
 }
 
-#[allow(clippy::not_unsafe_ptr_arg_deref)]
-pub fn mark(node: *mut Value) {
-    let mut all_allocations = ALL_ALLOCATIONS.lock().unwrap();
+impl Default for Heap {
+    fn default() -> Self {
+        Heap {
+            chunks: RefCell::new(Vec::new()),
+            free: RefCell::new(Vec::new()),
+            live: Cell::new(0),
+            live_after_collect: Cell::new(0),
 
-    let mut grey_objects = Vec::new();
+            phase: Cell::new(GCPhase::Idle),
+            white: Cell::new(GCItemState::White),
+            grey: RefCell::new(Vec::new()),
+            sweep_cursor: Cell::new(0),
 
-    if let Some(item) = all_allocations.get_mut(&(node as usize)) {
-        if *item == GCItemState::White {
-            *item = GCItemState::Grey;
-            grey_objects.push(node);
+            collections: Cell::new(0),
+            allocated: Cell::new(0),
+            swept: Cell::new(0),
         }
     }
+}
 
-    while !grey_objects.is_empty() {
-        let g = grey_objects.pop().unwrap();
-        if let Some(item) = all_allocations.get_mut(&(g as usize)) {
-            if *item == GCItemState::Grey {
-                *item = GCItemState::Black;
-
-                if let Some(children) = unsafe { (*g).referenced_children() } {
-                    for child in children {
-                        if let Some(item) = all_allocations.get_mut(&(child as usize)) {
-                            if *item == GCItemState::White {
-                                *item = GCItemState::Grey;
-                                grey_objects.push(child);
-                            }
-                        }
-                    }
+impl fmt::Debug for Heap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Heap")
+            .field("live", &self.live.get())
+            .field("capacity", &self.capacity())
+            .field("phase", &self.phase.get())
+            .finish()
+    }
+}
+
+impl Heap {
+    // Makes this heap the one alloc_new_value uses on this thread until the guard is dropped
+    pub fn enter(self: &Rc<Self>) -> HeapGuard {
+        let previous = CURRENT_HEAP.with(|current| current.borrow_mut().replace(self.clone()));
+        HeapGuard { previous }
+    }
+
+    pub fn alloc(&self, val: Value) -> *mut Value {
+        let mut free = self.free.borrow_mut();
+        if free.is_empty() {
+            let chunk: Box<[HeapCell]> = (0..CHUNK_SIZE)
+                .map(|_| HeapCell {
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                    state: Cell::new(GCItemState::Free),
+                })
+                .collect();
+            free.extend(chunk.iter().rev().map(|cell| cell as *const HeapCell));
+            self.chunks.borrow_mut().push(chunk);
+        }
+
+        let cell = unsafe { &*free.pop().unwrap() };
+        self.live.set(self.live.get() + 1);
+        self.allocated.set(self.allocated.get() + 1);
+        let has_children = matches!(val, Value::Array(_) | Value::Hash(_) | Value::Closure(_));
+        let ptr = cell.value.get() as *mut Value;
+        // The memory is uninitialized, so assigning would drop garbage
+        unsafe { ptr.write(val) };
+
+        match self.phase.get() {
+            GCPhase::Idle => cell.state.set(self.white.get()),
+            // Its children may not be marked yet, so it is traced like the roots
+            GCPhase::Mark if has_children => {
+                cell.state.set(GCItemState::Grey);
+                self.grey.borrow_mut().push((ptr, 0));
+            }
+            GCPhase::Mark | GCPhase::Sweep => cell.state.set(self.black()),
+        }
+        ptr
+    }
+
+    fn black(&self) -> GCItemState {
+        match self.white.get() {
+            GCItemState::White => GCItemState::Black,
+            _ => GCItemState::White,
+        }
+    }
+
+    pub fn phase(&self) -> GCPhase {
+        self.phase.get()
+    }
+
+    // Values still waiting to be traced in this cycle
+    pub fn grey_count(&self) -> usize {
+        self.grey.borrow().len()
+    }
+
+    // Roots must be shaded right after, and again before sweeping starts
+    pub fn start_marking(&self) {
+        self.phase.set(GCPhase::Mark);
+    }
+
+    pub fn start_sweeping(&self) {
+        self.phase.set(GCPhase::Sweep);
+        self.sweep_cursor.set(0);
+    }
+
+    // Schedules `ptr` for tracing if it is not marked yet.
+    // Every pointer reachable from it must come from this heap
+    pub fn shade(&self, ptr: *mut Value) {
+        let state = state_of(ptr);
+        if state.get() == self.white.get() {
+            state.set(GCItemState::Grey);
+            self.grey.borrow_mut().push((ptr, 0));
+        }
+    }
+
+    // Called after the contents of `ptr` are replaced in place: if it was already
+    // traced, even partly, it is traced again from the start so that its new children get marked
+    pub fn barrier(&self, ptr: *mut Value) {
+        let state = state_of(ptr);
+        if self.phase.get() == GCPhase::Mark
+            && (state.get() == self.black() || state.get() == GCItemState::Grey)
+        {
+            state.set(GCItemState::Grey);
+            self.grey.borrow_mut().push((ptr, 0));
+        }
+    }
+
+    // Called when `ptr` is stored somewhere that may already be traced
+    pub fn shade_stored(&self, ptr: *mut Value) {
+        if self.phase.get() == GCPhase::Mark {
+            self.shade(ptr);
+        }
+    }
+
+    // Traces grey values until about `work` values and children were visited, true once
+    // none are left. Big arrays and hashes are traced over several steps
+    pub fn mark_step(&self, work: usize) -> bool {
+        let mut work = work;
+        while work > 0 {
+            let (g, start) = match self.grey.borrow_mut().pop() {
+                Some(g) => g,
+                None => return true,
+            };
+            let value = unsafe { &*g };
+
+            let end = value.child_count().min(start.saturating_add(work));
+            for i in start..end {
+                if let Some(child) = value.child_at(i) {
+                    self.shade(child);
                 }
             }
+            work = work.saturating_sub(1 + end - start);
+
+            if end < value.child_count() {
+                self.grey.borrow_mut().push((g, end));
+            } else if state_of(g).get() == GCItemState::Grey {
+                state_of(g).set(self.black());
+            }
         }
+        self.grey.borrow().is_empty()
     }
-}
 
-pub fn sweep() {
-    let mut all_allocations = ALL_ALLOCATIONS.lock().unwrap();
-    let mut to_remove = Vec::new();
-    for (ptr, state) in all_allocations.iter() {
-        if *state == GCItemState::White {
-            unsafe {
-                dealloc(*ptr as *mut u8, LAYOUT);
+    // Sweeps up to `work` cells, freeing unmarked values, true once the cycle is over
+    pub fn sweep_step(&self, work: usize) -> bool {
+        let chunks = self.chunks.borrow();
+        let mut free = self.free.borrow_mut();
+        let end = self
+            .capacity()
+            .min(self.sweep_cursor.get().saturating_add(work));
+
+        for i in self.sweep_cursor.get()..end {
+            let cell = &chunks[i / CHUNK_SIZE][i % CHUNK_SIZE];
+            if cell.state.get() == self.white.get() {
+                // Frees what the value owns, such as string and array buffers
+                unsafe { std::ptr::drop_in_place(cell.value.get() as *mut Value) };
+                cell.state.set(GCItemState::Free);
+                free.push(cell as *const HeapCell);
+                self.live.set(self.live.get() - 1);
+                self.swept.set(self.swept.get() + 1);
             }
-            to_remove.push(*ptr);
         }
+        self.sweep_cursor.set(end);
+
+        if end < self.capacity() {
+            return false;
+        }
+
+        // Everything left is marked, and becomes unmarked for the next cycle
+        self.white.set(self.black());
+        self.phase.set(GCPhase::Idle);
+        self.live_after_collect.set(self.live.get());
+        self.collections.set(self.collections.get() + 1);
+        true
     }
 
-    // println!("Swept {} items", to_remove.len());
+    // Panics if one of `roots` or a child of a live value is not a live value of this heap.
+    // Only meaningful between cycles, when nothing is grey
+    pub fn verify(&self, roots: &[*mut Value]) {
+        let chunks = self.chunks.borrow();
+        let cell_size = std::mem::size_of::<HeapCell>();
+        let mut starts: Vec<usize> = chunks.iter().map(|c| c.as_ptr() as usize).collect();
+        starts.sort_unstable();
+
+        let check = |ptr: *mut Value, what: &dyn Fn() -> String| {
+            let address = ptr as usize;
+            let start = match starts.binary_search(&address) {
+                Ok(i) => starts[i],
+                Err(0) => panic!("GC verify: {} {ptr:?} is not in the heap", what()),
+                Err(i) => starts[i - 1],
+            };
+            if address - start >= CHUNK_SIZE * cell_size
+                || !(address - start).is_multiple_of(cell_size)
+            {
+                panic!("GC verify: {} {ptr:?} is not in the heap", what());
+            }
+            match state_of(ptr).get() {
+                GCItemState::Free => panic!("GC verify: {} {ptr:?} was freed", what()),
+                GCItemState::Grey => panic!("GC verify: {} {ptr:?} is still grey", what()),
+                _ => (),
+            }
+        };
+
+        for (i, root) in roots.iter().enumerate() {
+            check(*root, &|| format!("root {i}"));
+        }
 
-    for ptr in to_remove {
-        all_allocations.remove(&ptr);
+        for chunk in chunks.iter() {
+            for cell in chunk.iter() {
+                if cell.state.get() == GCItemState::Free {
+                    continue;
+                }
+                let value = unsafe { &*(cell.value.get() as *const Value) };
+                for i in 0..value.child_count() {
+                    if let Some(child) = value.child_at(i) {
+                        check(child, &|| format!("child {i} of {}", value.type_name()));
+                    }
+                }
+            }
+        }
+    }
+
+    // Number of values currently allocated
+    pub fn live(&self) -> usize {
+        self.live.get()
+    }
+
+    // Number of values allocated since the last collection
+    pub fn allocated_since_collect(&self) -> usize {
+        self.live
+            .get()
+            .saturating_sub(self.live_after_collect.get())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.chunks.borrow().len() * CHUNK_SIZE
+    }
+
+    // Bytes counted are those of the cells only, not of the buffers values own.
+    // The heap does not keep time, so pause is left to the VM
+    pub fn stats(&self) -> GCStats {
+        let cell_size = std::mem::size_of::<HeapCell>();
+        GCStats {
+            collections: self.collections.get(),
+            allocated: self.allocated.get(),
+            bytes_allocated: self.allocated.get() * cell_size,
+            swept: self.swept.get(),
+            live: self.live.get(),
+            heap_bytes: self.capacity() * cell_size,
+            pause: Duration::ZERO,
+        }
     }
+}
 
-    for (_, state) in all_allocations.iter_mut() {
-        *state = GCItemState::White;
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for chunk in self.chunks.get_mut().iter_mut() {
+            for cell in chunk.iter_mut() {
+                if cell.state.get() != GCItemState::Free {
+                    unsafe { cell.value.get_mut().assume_init_drop() };
+                }
+            }
+        }
     }
+}
 
-    unsafe {
-        LAST_ALLOCATED = all_allocations.len();
-    };
+fn state_of<'a>(ptr: *mut Value) -> &'a Cell<GCItemState> {
+    unsafe { &(*(ptr as *const HeapCell)).state }
+}
+
+// Allocates in the heap of the VM that is running on this thread
+pub fn alloc_new_value(val: Value) -> *mut Value {
+    CURRENT_HEAP.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .expect("Values can only be allocated while a VM heap is entered")
+            .alloc(val)
+    })
 }
@@ -1,4 +1,9 @@
 pub mod bytecode;
+pub mod bytecode_file;
+pub mod folding;
 pub mod memory;
+pub mod options;
+pub mod output;
 pub mod value;
+pub mod verifier;
 pub mod vm_bc;
@@ -0,0 +1,84 @@
+use super::memory::{GC_STEP_WORK, GC_THRESHOLD};
+
+// Values on the stack and nested calls allowed by default
+pub const STACK_SIZE: usize = 1 << 16;
+pub const FRAMES_SIZE: usize = 4096;
+
+// Limits and collector settings of a VM, built like
+// `VMOptions::default().max_heap(1 << 20).max_frames(256)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VMOptions {
+    // Values allocated between collection cycles
+    pub gc_threshold: usize,
+    // Values marked or cells swept by each step of a cycle
+    pub gc_step_work: usize,
+    // Checks the heap for dangling pointers after every cycle, panicking on the first one
+    pub gc_verify: bool,
+    // Values alive at once, going over it even after a full collection is a runtime error
+    pub max_heap: Option<usize>,
+    // Slots on the value stack, locals of every frame included
+    pub stack_size: usize,
+    // Calls in progress at once
+    pub max_frames: usize,
+    // Instructions each execution may run before it is stopped
+    pub max_instructions: Option<u64>,
+    // Lets optimize replace common sequences of instructions with single ones
+    pub superinstructions: bool,
+}
+
+impl Default for VMOptions {
+    fn default() -> Self {
+        VMOptions {
+            gc_threshold: GC_THRESHOLD,
+            gc_step_work: GC_STEP_WORK,
+            gc_verify: false,
+            max_heap: None,
+            stack_size: STACK_SIZE,
+            max_frames: FRAMES_SIZE,
+            max_instructions: None,
+            superinstructions: true,
+        }
+    }
+}
+
+impl VMOptions {
+    pub fn gc_threshold(mut self, values: usize) -> Self {
+        self.gc_threshold = values;
+        self
+    }
+
+    pub fn gc_step_work(mut self, work: usize) -> Self {
+        self.gc_step_work = work;
+        self
+    }
+
+    pub fn gc_verify(mut self, verify: bool) -> Self {
+        self.gc_verify = verify;
+        self
+    }
+
+    pub fn max_heap(mut self, values: usize) -> Self {
+        self.max_heap = Some(values);
+        self
+    }
+
+    pub fn stack_size(mut self, slots: usize) -> Self {
+        self.stack_size = slots;
+        self
+    }
+
+    pub fn max_frames(mut self, frames: usize) -> Self {
+        self.max_frames = frames;
+        self
+    }
+
+    pub fn max_instructions(mut self, instructions: u64) -> Self {
+        self.max_instructions = Some(instructions);
+        self
+    }
+
+    pub fn superinstructions(mut self, enabled: bool) -> Self {
+        self.superinstructions = enabled;
+        self
+    }
+}
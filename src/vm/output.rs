@@ -0,0 +1,43 @@
+use std::fmt;
+use std::io::{self, Write};
+
+// Where `echo` and `debug` print to, one line per statement
+#[derive(Default)]
+pub enum OutputSink {
+    #[default]
+    Stdout,
+    Writer(Box<dyn Write>),
+    // Called with each line, without its newline
+    Callback(Box<dyn FnMut(&str)>),
+}
+
+impl OutputSink {
+    pub fn writer(writer: impl Write + 'static) -> Self {
+        OutputSink::Writer(Box::new(writer))
+    }
+
+    pub fn callback(callback: impl FnMut(&str) + 'static) -> Self {
+        OutputSink::Callback(Box::new(callback))
+    }
+
+    pub fn print_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            OutputSink::Stdout => writeln!(io::stdout().lock(), "{line}"),
+            OutputSink::Writer(w) => writeln!(w, "{line}"),
+            OutputSink::Callback(f) => {
+                f(line);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for OutputSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputSink::Stdout => write!(f, "Stdout"),
+            OutputSink::Writer(_) => write!(f, "Writer"),
+            OutputSink::Callback(_) => write!(f, "Callback"),
+        }
+    }
+}
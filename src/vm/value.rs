@@ -1,5 +1,11 @@
 #![allow(clippy::not_unsafe_ptr_arg_deref)]
 
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use indexmap::IndexMap;
+
 use crate::memory::alloc_new_value;
 
 pub enum BinOpResult {
@@ -8,6 +14,74 @@ pub enum BinOpResult {
     NoMatch,
 }
 
+// Values usable as hash keys, compared by value
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HashKey {
+    Int(i64),
+    String(String),
+    Bool(bool),
+    Null,
+}
+
+impl HashKey {
+    pub fn to_value(&self) -> Value {
+        match self {
+            HashKey::Int(i) => Value::Int(*i),
+            HashKey::String(s) => Value::String(s.clone()),
+            HashKey::Bool(b) => Value::Bool(*b),
+            HashKey::Null => Value::Null,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    // Address of the first bytecode of the body
+    pub address: usize,
+    // Number of local slots reserved for each call, including parameters
+    pub locals: usize,
+    // Variables captured when the closure is made: (is a local of the enclosing function, index)
+    // Indexes either the enclosing function's locals or its own upvalues
+    pub upvalues: Vec<(bool, usize)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Upvalue {
+    // Stack index of the captured variable, while its frame is alive
+    Open(usize),
+    Closed(*mut Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+pub type NativeFn = Box<dyn Fn(&[Value]) -> Result<Value, String>>;
+
+// Function implemented by the host, see VM::register_native
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NativeFunction({}, {})", self.name, self.arity)
+    }
+}
+
+// Closures can't be compared, so natives are only equal to themselves
+impl PartialEq for NativeFunction {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
@@ -16,8 +90,20 @@ pub enum Value {
     String(String),
     Bool(bool),
     Null,
+    // Integers from start to end, never stored as an array
+    Range {
+        start: i64,
+        end: i64,
+        inclusive: bool,
+    },
 
     Array(Vec<*mut Value>),
+    // Keeps insertion order
+    Hash(Box<IndexMap<HashKey, *mut Value>>),
+    // Shared, since functions are cloned out of the constants on every load
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    NativeFunction(Rc<NativeFunction>),
 }
 
 impl Value {
@@ -28,6 +114,11 @@ impl Value {
             Value::String(s) => format!("{s:?}"),
             Value::Bool(b) => format!("{b:?}"),
             Value::Null => "null".to_string(),
+            Value::Range {
+                start,
+                end,
+                inclusive,
+            } => format!("{start}{}{end}", if *inclusive { ".." } else { "..." }),
 
             Value::Array(a) => format!(
                 "[{}]",
@@ -36,6 +127,20 @@ impl Value {
                     .collect::<Vec<_>>()
                     .join(", ")
             ),
+            Value::Hash(h) => format!(
+                "{{{}}}",
+                h.iter()
+                    .map(|(k, v)| format!(
+                        "{}: {}",
+                        k.to_value().debug_format(),
+                        unsafe { &**v }.debug_format()
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Value::Function(f) => format!("<function {}>", f.name),
+            Value::Closure(c) => format!("<function {}>", c.function.name),
+            Value::NativeFunction(n) => format!("<native function {}>", n.name),
         }
     }
 
@@ -46,6 +151,11 @@ impl Value {
             Value::String(s) => s.to_string(),
             Value::Bool(b) => format!("{b}"),
             Value::Null => "null".to_string(),
+            Value::Range {
+                start,
+                end,
+                inclusive,
+            } => format!("{start}{}{end}", if *inclusive { ".." } else { "..." }),
 
             Value::Array(a) => format!(
                 "[{}]",
@@ -54,6 +164,20 @@ impl Value {
                     .collect::<Vec<_>>()
                     .join(", ")
             ),
+            Value::Hash(h) => format!(
+                "{{{}}}",
+                h.iter()
+                    .map(|(k, v)| format!(
+                        "{}: {}",
+                        k.to_value().debug_format(),
+                        unsafe { &**v }.debug_format()
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Value::Function(f) => format!("<function {}>", f.name),
+            Value::Closure(c) => format!("<function {}>", c.function.name),
+            Value::NativeFunction(n) => format!("<native function {}>", n.name),
         }
     }
 
@@ -64,8 +188,11 @@ impl Value {
             Value::String(_) => "string",
             Value::Bool(_) => "bool",
             Value::Null => "null",
+            Value::Range { .. } => "range",
 
             Value::Array(_) => "array",
+            Value::Hash(_) => "hash",
+            Value::Function(_) | Value::Closure(_) | Value::NativeFunction(_) => "function",
         }
     }
 
@@ -79,7 +206,7 @@ impl Value {
 
     pub fn shallow_copy(&mut self) -> *mut Value {
         match self {
-            Value::Array(_) => self as *mut Value,
+            Value::Array(_) | Value::Hash(_) => self as *mut Value,
             _ => alloc_new_value(self.clone()),
         }
     }
@@ -91,13 +218,34 @@ impl Value {
                     .map(|v| unsafe { &mut **v }.deep_copy())
                     .collect(),
             )),
+            Value::Hash(h) => alloc_new_value(Value::Hash(Box::new(
+                h.iter()
+                    .map(|(k, v)| (k.clone(), unsafe { &mut **v }.deep_copy()))
+                    .collect(),
+            ))),
             _ => alloc_new_value(self.clone()),
         }
     }
 
-    pub fn referenced_children(&self) -> Option<Vec<*mut Value>> {
+    // Number of heap values this one may point to, see child_at
+    pub fn child_count(&self) -> usize {
         match self {
-            Value::Array(a) => Some(a.clone()),
+            Value::Array(a) => a.len(),
+            Value::Hash(h) => h.len(),
+            Value::Closure(c) => c.upvalues.len(),
+            _ => 0,
+        }
+    }
+
+    pub fn child_at(&self, index: usize) -> Option<*mut Value> {
+        match self {
+            Value::Array(a) => a.get(index).copied(),
+            Value::Hash(h) => h.get_index(index).map(|(_, v)| *v),
+            Value::Closure(c) => match *c.upvalues.get(index)?.borrow() {
+                Upvalue::Closed(v) => Some(v),
+                // Open upvalues live on the stack, which is already a root
+                Upvalue::Open(_) => None,
+            },
             _ => None,
         }
     }
@@ -109,8 +257,11 @@ impl Value {
             Value::String(s) => !s.is_empty(),
             Value::Bool(b) => *b,
             Value::Null => false,
+            Value::Range { .. } => !self.range_bounds().is_empty(),
 
             Value::Array(a) => !a.is_empty(),
+            Value::Hash(h) => !h.is_empty(),
+            Value::Function(_) | Value::Closure(_) | Value::NativeFunction(_) => true,
         }
     }
 
@@ -121,6 +272,18 @@ impl Value {
             (Value::String(s1), Value::String(s2)) => *s1 == *s2,
             (Value::Bool(b1), Value::Bool(b2)) => b1 == b2,
             (Value::Null, Value::Null) => true,
+            (
+                Value::Range {
+                    start: s1,
+                    end: e1,
+                    inclusive: i1,
+                },
+                Value::Range {
+                    start: s2,
+                    end: e2,
+                    inclusive: i2,
+                },
+            ) => s1 == s2 && e1 == e2 && i1 == i2,
 
             (Value::Array(a1), Value::Array(a2)) => {
                 if a1.len() != a2.len() {
@@ -136,6 +299,25 @@ impl Value {
                 true
             }
 
+            (Value::Hash(h1), Value::Hash(h2)) => {
+                h1.len() == h2.len()
+                    && h1.iter().all(|(k, v1)| match h2.get(k) {
+                        Some(v2) => unsafe { &**v1 }.is_equal(unsafe { &**v2 }),
+                        None => false,
+                    })
+            }
+
+            (Value::Function(f1), Value::Function(f2)) => f1 == f2,
+            (Value::NativeFunction(n1), Value::NativeFunction(n2)) => Rc::ptr_eq(n1, n2),
+            (Value::Closure(c1), Value::Closure(c2)) => {
+                c1.function == c2.function
+                    && c1
+                        .upvalues
+                        .iter()
+                        .zip(c2.upvalues.iter())
+                        .all(|(u1, u2)| Rc::ptr_eq(u1, u2))
+            }
+
             _ => false,
         }
     }
@@ -170,6 +352,70 @@ impl Value {
         }
     }
 
+    pub fn make_range(&self, other: &Value, inclusive: bool) -> BinOpResult {
+        match (self, other) {
+            (Value::Int(i1), Value::Int(i2)) => BinOpResult::Ok(alloc_new_value(Value::Range {
+                start: *i1,
+                end: *i2,
+                inclusive,
+            })),
+
+            _ => BinOpResult::NoMatch,
+        }
+    }
+
+    // Integers covered by a range, as an exclusive Rust range
+    pub fn range_bounds(&self) -> std::ops::Range<i64> {
+        match self {
+            Value::Range {
+                start,
+                end,
+                inclusive,
+            } => {
+                *start..if *inclusive {
+                    end.saturating_add(1)
+                } else {
+                    *end
+                }
+            }
+            _ => 0..0,
+        }
+    }
+
+    // Positions selected by slicing a sequence of `length` items with this range
+    // Negative bounds count from the end
+    pub fn slice_bounds(&self, length: usize) -> Result<std::ops::Range<usize>, String> {
+        let (start, end, inclusive) = match self {
+            Value::Range {
+                start,
+                end,
+                inclusive,
+            } => (*start, *end, *inclusive),
+            _ => return Ok(0..0),
+        };
+
+        let length = length as i64;
+        let out_of_range = || {
+            format!(
+                "Slice out of range: {} for length {length}",
+                self.debug_format()
+            )
+        };
+        let start = if start < 0 { start + length } else { start };
+        let mut end = if end < 0 { end + length } else { end };
+        if inclusive {
+            end = end.checked_add(1).ok_or_else(out_of_range)?;
+        }
+
+        if start < 0 || start > length || end < 0 || end > length {
+            return Err(out_of_range());
+        }
+        if end < start {
+            return Ok(start as usize..start as usize);
+        }
+        Ok(start as usize..end as usize)
+    }
+
     pub fn binary_sub(&self, other: &Value) -> BinOpResult {
         match (self, other) {
             (Value::Float(f1), Value::Float(f2)) => {
@@ -434,41 +680,129 @@ impl Value {
         }
     }
 
+    // Item of a for loop at iteration `state`, along with the next state
+    // Strings keep the byte offset of the next character as their state
+    pub fn iterate(&self, state: i64) -> Result<Option<(*mut Value, i64)>, String> {
+        let i = state as usize;
+        match self {
+            Value::Array(a) => Ok(a.get(i).map(|v| (*v, state + 1))),
+            Value::String(s) => {
+                let rest = s
+                    .get(i..)
+                    .ok_or_else(|| format!("Invalid string iteration state: {state}"))?;
+                Ok(rest.chars().next().map(|c| {
+                    (
+                        alloc_new_value(Value::String(c.to_string())),
+                        state + c.len_utf8() as i64,
+                    )
+                }))
+            }
+            Value::Hash(h) => Ok(h
+                .get_index(i)
+                .map(|(k, _)| (alloc_new_value(k.to_value()), state + 1))),
+            Value::Range { .. } => {
+                let bounds = self.range_bounds();
+                Ok(if bounds.start + state < bounds.end {
+                    Some((alloc_new_value(Value::Int(bounds.start + state)), state + 1))
+                } else {
+                    None
+                })
+            }
+            // Integers count from 0 up to, but excluding, themselves
+            Value::Int(n) => Ok(if state < *n {
+                Some((alloc_new_value(Value::Int(state)), state + 1))
+            } else {
+                None
+            }),
+            _ => Err(format!("Cannot iterate over type {}", self.type_name())),
+        }
+    }
+
+    pub fn hash_key(&self) -> Result<HashKey, String> {
+        match self {
+            Value::Int(i) => Ok(HashKey::Int(*i)),
+            Value::String(s) => Ok(HashKey::String(s.clone())),
+            Value::Bool(b) => Ok(HashKey::Bool(*b)),
+            Value::Null => Ok(HashKey::Null),
+            _ => Err(format!("Unhashable type: {}", self.type_name())),
+        }
+    }
+
+    // Like get_element, but a missing hash key is inserted as null so that it can be assigned
+    pub fn get_or_insert_element(&mut self, index: *mut Value) -> Result<*mut Value, String> {
+        match self {
+            Value::Hash(h) => {
+                let key = unsafe { (*index).hash_key()? };
+                Ok(*h.entry(key).or_insert_with(|| alloc_new_value(Value::Null)))
+            }
+            // Both would only produce a copy, so the assignment would be lost
+            Value::String(_) => Err("Cannot assign to an element of a string".to_string()),
+            _ if matches!(unsafe { &*index }, Value::Range { .. }) => {
+                Err("Cannot assign to a slice".to_string())
+            }
+            _ => self.get_element(index),
+        }
+    }
+
+    // Position of `index` in a sequence of `length` items, where negative indexes count from the end
+    pub fn element_position(index: i64, length: usize) -> Result<usize, String> {
+        let position = if index < 0 {
+            index + length as i64
+        } else {
+            index
+        };
+        if position < 0 || position >= length as i64 {
+            Err(format!("Index out of range: {index} for length {length}"))
+        } else {
+            Ok(position as usize)
+        }
+    }
+
     pub fn get_element(&self, index: *mut Value) -> Result<*mut Value, String> {
         unsafe {
             match self {
                 Value::Array(a) => {
-                    if let Value::Int(i) = *index {
-                        if i < 0 {
-                            Err(format!("Negative index not supported: {i}"))
-                        } else if let Some(v) = a.get(i as usize) {
-                            Ok(*v)
-                        } else {
-                            Err(format!("Index out of range: {i}"))
-                        }
+                    if let Value::Range { .. } = *index {
+                        let bounds = (*index).slice_bounds(a.len())?;
+                        Ok(alloc_new_value(Value::Array(
+                            a[bounds].iter().map(|v| (**v).shallow_copy()).collect(),
+                        )))
+                    } else if let Value::Int(i) = *index {
+                        Ok(a[Value::element_position(i, a.len())?])
                     } else {
                         Err(format!(
-                            "Array index must be an integer, not {}",
+                            "Array index must be an integer or range, not {}",
                             (*index).type_name()
                         ))
                     }
                 }
                 Value::String(s) => {
-                    if let Value::Int(i) = *index {
-                        if i < 0 {
-                            Err(format!("Negative index not supported: {i}"))
-                        } else if let Some(c) = s.chars().nth(i as usize) {
-                            Ok(alloc_new_value(Value::String(c.to_string())))
-                        } else {
-                            Err(format!("Index out of range: {i}"))
-                        }
+                    if let Value::Range { .. } = *index {
+                        let bounds = (*index).slice_bounds(s.chars().count())?;
+                        Ok(alloc_new_value(Value::String(
+                            s.chars()
+                                .skip(bounds.start)
+                                .take(bounds.end - bounds.start)
+                                .collect(),
+                        )))
+                    } else if let Value::Int(i) = *index {
+                        let position = Value::element_position(i, s.chars().count())?;
+                        let c = s.chars().nth(position).unwrap();
+                        Ok(alloc_new_value(Value::String(c.to_string())))
                     } else {
                         Err(format!(
-                            "String index must be an integer, not {}",
+                            "String index must be an integer or range, not {}",
                             (*index).type_name()
                         ))
                     }
                 }
+                Value::Hash(h) => {
+                    let key = (*index).hash_key()?;
+                    Ok(match h.get(&key) {
+                        Some(v) => *v,
+                        None => alloc_new_value(Value::Null),
+                    })
+                }
                 _ => Err(format!("Cannot get element from type {}", self.type_name())),
             }
         }
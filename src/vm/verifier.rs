@@ -0,0 +1,287 @@
+// Checks bytecode before it runs, so that bytecode loaded from a file or rewritten by the
+// optimizer cannot make execute index out of the stack, the constants or the code
+//
+// Code is followed from the entry and from the address of every function like execute
+// would, tracking how many temporaries are on the stack above the locals. Every address
+// reached must be the start of an instruction, with the same depth on every path to it, and
+// only be reached from one function, so that it is checked against that function's locals.
+
+use std::fmt;
+
+use crate::value::Value;
+use crate::vm_bc::VM;
+
+use super::bytecode::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyErrorKind {
+    UnknownBytecode(Byte),
+    // The instruction needs more operands than the code has left
+    MissingOperands,
+    JumpOutOfRange(usize),
+    // An address that is reached is in the middle of another instruction
+    InsideInstruction(usize),
+    ConstantOutOfRange(usize),
+    NotAFunction(usize),
+    // The function constant starts past the end of the code
+    FunctionOutOfRange(usize),
+    // The function constant has upvalues, so it can only be loaded by MAKE_CLOSURE
+    NotAClosure(usize),
+    LocalOutOfRange(usize),
+    GlobalOutOfRange(usize),
+    UpvalueOutOfRange(usize),
+    // The comparison operand of COMPARE_LOCAL_JUMP is not a comparison bytecode
+    NotAComparison(Byte),
+    // Pops more values than the stack holds: (needed, depth)
+    StackUnderflow(usize, usize),
+    // Reached with different depths on different paths: (first depth, other depth)
+    StackMismatch(usize, usize),
+    // Jumps or runs into the code of another function or of the top level
+    OtherRegion(usize),
+    // Code that runs off the end of a function without returning
+    MissingReturn,
+    ReturnOutsideFunction,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    // Of the instruction at fault
+    pub address: usize,
+    pub kind: VerifyErrorKind,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid bytecode at {:04x}: ", self.address)?;
+        match self.kind {
+            VerifyErrorKind::UnknownBytecode(b) => write!(f, "unknown bytecode {b}"),
+            VerifyErrorKind::MissingOperands => write!(f, "missing operands"),
+            VerifyErrorKind::JumpOutOfRange(t) => write!(f, "jump to {t:04x} is out of range"),
+            VerifyErrorKind::InsideInstruction(t) => {
+                write!(f, "{t:04x} is inside another instruction")
+            }
+            VerifyErrorKind::ConstantOutOfRange(i) => write!(f, "no constant {i}"),
+            VerifyErrorKind::NotAFunction(i) => write!(f, "constant {i} is not a function"),
+            VerifyErrorKind::FunctionOutOfRange(i) => {
+                write!(f, "function {i} starts past the end of the code")
+            }
+            VerifyErrorKind::NotAClosure(i) => {
+                write!(f, "function {i} has upvalues but is not made a closure")
+            }
+            VerifyErrorKind::LocalOutOfRange(i) => write!(f, "no local {i}"),
+            VerifyErrorKind::GlobalOutOfRange(i) => write!(f, "no global {i}"),
+            VerifyErrorKind::UpvalueOutOfRange(i) => write!(f, "no upvalue {i}"),
+            VerifyErrorKind::NotAComparison(b) => write!(f, "{b} is not a comparison"),
+            VerifyErrorKind::StackUnderflow(needed, depth) => {
+                write!(f, "needs {needed} values on the stack, has {depth}")
+            }
+            VerifyErrorKind::StackMismatch(a, b) => {
+                write!(f, "stack depth is {a} on one path and {b} on another")
+            }
+            VerifyErrorKind::OtherRegion(t) => {
+                write!(f, "{t:04x} is in the code of another function")
+            }
+            VerifyErrorKind::MissingReturn => write!(f, "function does not return"),
+            VerifyErrorKind::ReturnOutsideFunction => write!(f, "return outside of a function"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+// Code of the top level or of one function
+#[derive(PartialEq)]
+struct Region {
+    start: usize,
+    locals: usize,
+    upvalues: usize,
+    is_function: bool,
+}
+
+impl VM {
+    // Slots the top level reserves for its variables, see execute
+    pub fn global_slots(&self) -> usize {
+        self.current_compiler
+            .max_count
+            .max(self.current_compiler.count)
+    }
+
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let mut regions = vec![Region {
+            start: self.entry,
+            locals: self.global_slots(),
+            upvalues: 0,
+            is_function: false,
+        }];
+        for (i, constant) in self.constants.iter().enumerate() {
+            if let Value::Function(f) = constant {
+                if f.address > self.bytecodes.len() {
+                    return Err(VerifyError {
+                        address: f.address,
+                        kind: VerifyErrorKind::FunctionOutOfRange(i),
+                    });
+                }
+                let region = Region {
+                    start: f.address,
+                    locals: f.locals,
+                    upvalues: f.upvalues.len(),
+                    is_function: true,
+                };
+                // The same function may be in the constants more than once
+                if !regions.contains(&region) {
+                    regions.push(region);
+                }
+            }
+        }
+
+        // Depth and region of each instruction reached, and whether an address holds an operand
+        let mut depths: Vec<Option<(usize, usize)>> = vec![None; self.bytecodes.len()];
+        let mut operand = vec![false; self.bytecodes.len()];
+        for (i, region) in regions.iter().enumerate() {
+            self.verify_region(i, region, &mut depths, &mut operand)?;
+        }
+        Ok(())
+    }
+
+    fn verify_region(
+        &self,
+        index: usize,
+        region: &Region,
+        depths: &mut [Option<(usize, usize)>],
+        operand: &mut [bool],
+    ) -> Result<(), VerifyError> {
+        let len = self.bytecodes.len();
+        // (address, depth) still to follow
+        let mut pending = vec![(region.start, 0)];
+
+        while let Some((pc, depth)) = pending.pop() {
+            let error = |kind| Err(VerifyError { address: pc, kind });
+            if pc == len {
+                if region.is_function {
+                    return error(VerifyErrorKind::MissingReturn);
+                }
+                continue;
+            }
+            if operand[pc] {
+                return error(VerifyErrorKind::InsideInstruction(pc));
+            }
+            match depths[pc] {
+                Some((_, r)) if r != index => return error(VerifyErrorKind::OtherRegion(pc)),
+                Some((d, _)) if d == depth => continue,
+                Some((d, _)) => return error(VerifyErrorKind::StackMismatch(d, depth)),
+                None => depths[pc] = Some((depth, index)),
+            }
+
+            let bc = self.bytecodes[pc];
+            if bytecode_name(bc) == "UNKNOWN" {
+                return error(VerifyErrorKind::UnknownBytecode(bc));
+            }
+            let count = operands(bc);
+            if pc + count >= len {
+                return error(VerifyErrorKind::MissingOperands);
+            }
+            for i in pc + 1..=pc + count {
+                if depths[i].is_some() {
+                    return error(VerifyErrorKind::InsideInstruction(i));
+                }
+                operand[i] = true;
+            }
+            let arg = |i: usize| self.bytecodes[pc + i] as usize;
+            let next = pc + 1 + count;
+
+            // Values popped, values pushed
+            let (pops, pushes) = match bc {
+                NOOP | JUMP | INCREMENT_LOCAL | COMPARE_LOCAL_JUMP => (0, 0),
+                LOAD_CONST | LOAD_LOCAL | LOAD_GLOBAL | LOAD_UPVALUE | MAKE_CLOSURE
+                | ADD_LOCALS => (0, 1),
+                POP_LAST | REPLACE | REPLACE_GLOBAL | REPLACE_UPVALUE | DEBUG_PRINT
+                | ECHO_PRINT | ITER_START | JUMP_IF_FALSE | RETURN => (1, 0),
+                JUMP_IF_FALSE_NO_POP | UNARY_NEG | UNARY_NOT => (1, 1),
+                FOR_ITER => (0, 1),
+                MAKE_ARRAY | MAKE_STRING => (arg(1), 1),
+                MAKE_HASH => (arg(1) * 2, 1),
+                CALL => (arg(1) + 1, 1),
+                _ => (2, 1),
+            };
+            if pops > depth {
+                return error(VerifyErrorKind::StackUnderflow(pops, depth));
+            }
+
+            match bc {
+                LOAD_CONST if arg(1) >= self.constants.len() => {
+                    return error(VerifyErrorKind::ConstantOutOfRange(arg(1)))
+                }
+                LOAD_CONST => {
+                    if let Value::Function(f) = &self.constants[arg(1)] {
+                        if !f.upvalues.is_empty() {
+                            return error(VerifyErrorKind::NotAClosure(arg(1)));
+                        }
+                    }
+                }
+                // Captures the locals of this region or its own upvalues, so the top level
+                // can only capture its variables
+                MAKE_CLOSURE => match self.constants.get(arg(1)) {
+                    Some(Value::Function(f)) => {
+                        for (is_local, index) in f.upvalues.iter().copied() {
+                            if is_local && index >= region.locals {
+                                return error(VerifyErrorKind::LocalOutOfRange(index));
+                            }
+                            if !is_local && index >= region.upvalues {
+                                return error(VerifyErrorKind::UpvalueOutOfRange(index));
+                            }
+                        }
+                    }
+                    Some(_) => return error(VerifyErrorKind::NotAFunction(arg(1))),
+                    None => return error(VerifyErrorKind::ConstantOutOfRange(arg(1))),
+                },
+                RETURN if !region.is_function => {
+                    return error(VerifyErrorKind::ReturnOutsideFunction)
+                }
+                LOAD_LOCAL | REPLACE | INCREMENT_LOCAL | COMPARE_LOCAL_JUMP | ADD_LOCALS
+                    if arg(1) >= region.locals =>
+                {
+                    return error(VerifyErrorKind::LocalOutOfRange(arg(1)))
+                }
+                ADD_LOCALS if arg(2) >= region.locals => {
+                    return error(VerifyErrorKind::LocalOutOfRange(arg(2)))
+                }
+                INCREMENT_LOCAL | COMPARE_LOCAL_JUMP if arg(2) >= self.constants.len() => {
+                    return error(VerifyErrorKind::ConstantOutOfRange(arg(2)))
+                }
+                COMPARE_LOCAL_JUMP if !(BINARY_EQ..=BINARY_GE).contains(&(arg(3) as Byte)) => {
+                    return error(VerifyErrorKind::NotAComparison(arg(3) as Byte))
+                }
+                // The iterable and its state take two slots
+                ITER_START | FOR_ITER if arg(1) + 1 >= region.locals => {
+                    return error(VerifyErrorKind::LocalOutOfRange(arg(1) + 1))
+                }
+                LOAD_GLOBAL | REPLACE_GLOBAL if arg(1) >= self.global_slots() => {
+                    return error(VerifyErrorKind::GlobalOutOfRange(arg(1)))
+                }
+                LOAD_UPVALUE | REPLACE_UPVALUE if arg(1) >= region.upvalues => {
+                    return error(VerifyErrorKind::UpvalueOutOfRange(arg(1)))
+                }
+                _ => (),
+            }
+
+            let depth = depth - pops + pushes;
+            let target = match bc {
+                JUMP | JUMP_IF_FALSE | JUMP_IF_FALSE_NO_POP => Some(arg(1)),
+                FOR_ITER => Some(arg(2)),
+                COMPARE_LOCAL_JUMP => Some(arg(4)),
+                _ => None,
+            };
+            if let Some(target) = target {
+                if target > len {
+                    return error(VerifyErrorKind::JumpOutOfRange(target));
+                }
+                // FOR_ITER only pushes the item when it does not jump
+                pending.push((target, if bc == FOR_ITER { depth - 1 } else { depth }));
+            }
+            if bc != JUMP && bc != RETURN {
+                pending.push((next, depth));
+            }
+        }
+        Ok(())
+    }
+}
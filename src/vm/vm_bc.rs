@@ -1,21 +1,23 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use arrayvec::ArrayVec;
+use indexmap::IndexMap;
 
 use crate::ast::*;
+use crate::error::*;
 use crate::value::*;
 
 use super::bytecode::*;
 use super::memory::*;
-
-pub const GC_TRIGGER: usize = 1 << 20;
+use super::options::*;
+use super::output::OutputSink;
 
 pub const BYTECODE_CAP: usize = 32768;
-pub const CONSTANT_SIZE: usize = 4096;
-pub const LOCAL_SIZE: usize = 4096;
-pub const SCOPE_SIZE: usize = 512;
-pub const STACK_SIZE: usize = 1 << 16;
 
 pub const BOOL_FALSE_CONSTANT: usize = 0;
 pub const BOOL_TRUE_CONSTANT: usize = 1;
@@ -23,57 +25,119 @@ pub const NULL_CONSTANT: usize = 2;
 
 #[derive(Debug, Clone, Default)]
 pub struct Compiler {
-    pub local_map: ArrayVec<HashMap<String, usize>, SCOPE_SIZE>,
+    pub local_map: Vec<HashMap<String, usize>>,
     pub scope_depth: usize,
     pub count: usize,
+    // Most locals alive at once, reserved on the stack for every call
+    pub max_count: usize,
+    // Variables of enclosing functions captured by this one, see Function::upvalues
+    pub upvalues: Vec<(bool, usize)>,
+    // Slots of locals captured by inner closures
+    pub captured: HashSet<usize>,
+}
+
+impl Compiler {
+    pub fn resolve(&self, name: &str) -> Option<usize> {
+        for i in (0..=self.scope_depth).rev() {
+            if let Some(index) = self.local_map[i].get(name) {
+                return Some(*index);
+            }
+        }
+        None
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
+pub struct CallFrame {
+    pub return_address: usize,
+    // Base of the caller, restored on return
+    pub base: usize,
+}
+
+// Value kept alive for the host, see VM::add_root
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RootHandle(usize);
+
+#[derive(Debug)]
 pub struct VM {
     pub source: String,
+    pub source_offset: usize,
 
     pub bytecodes: Vec<Byte>,
     // (Start, End)
     pub lines: Vec<AstSpan>,
     pub pc: usize,
+    // Where execution of the last compiled program starts
+    pub entry: usize,
 
-    pub constants: ArrayVec<Value, CONSTANT_SIZE>,
+    pub constants: Vec<Value>,
     pub constant_hash_int: HashMap<i64, Byte>,
 
     pub break_jump_patches: Vec<Vec<usize>>,
     pub next_jump_patches: Vec<Vec<usize>>,
 
     pub current_compiler: Compiler,
-
-    pub stack: ArrayVec<*mut Value, STACK_SIZE>,
+    // Compilers of the functions enclosing the one being compiled, outermost first
+    pub enclosing_compilers: Vec<Compiler>,
+
+    pub stack: Vec<*mut Value>,
+    pub frames: Vec<CallFrame>,
+    // Start of the current call frame's locals on the stack
+    pub base: usize,
+    // Upvalues still pointing into the stack, closed when their frame returns
+    pub open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+
+    // Owns every value the VM allocates, entered while the VM runs
+    pub heap: Rc<Heap>,
+    pub options: VMOptions,
+    // Time spent collecting so far
+    pub gc_pause: Duration,
+    pub host_roots: Vec<Option<*mut Value>>,
 
     pub last_popped: Option<*mut Value>,
     pub repl_mode: bool,
+    pub output: OutputSink,
+    // Set from any thread to stop the running program, cleared once it stopped
+    pub interrupt: Arc<AtomicBool>,
 
-    pub error: Option<String>,
+    pub error: Option<GlacierError>,
 }
 
 impl Default for VM {
     fn default() -> Self {
+        let heap = Rc::new(Heap::default());
         let mut v = VM {
             source: String::new(),
+            source_offset: 0,
 
             bytecodes: Vec::with_capacity(BYTECODE_CAP),
             lines: Vec::with_capacity(BYTECODE_CAP),
             pc: 0,
+            entry: 0,
 
-            constants: ArrayVec::new(),
+            constants: Vec::new(),
             constant_hash_int: HashMap::new(),
 
             break_jump_patches: Vec::new(),
             next_jump_patches: Vec::new(),
 
             current_compiler: Default::default(),
+            enclosing_compilers: Vec::new(),
 
-            stack: ArrayVec::new(),
+            stack: Vec::with_capacity(STACK_SIZE),
+            frames: Vec::new(),
+            base: 0,
+            open_upvalues: Vec::new(),
+
+            heap,
+            options: VMOptions::default(),
+            gc_pause: Duration::ZERO,
+            host_roots: Vec::new(),
 
             last_popped: None,
             repl_mode: false,
+            output: OutputSink::default(),
+            interrupt: Arc::new(AtomicBool::new(false)),
 
             error: None,
         };
@@ -89,69 +153,70 @@ impl Default for VM {
 }
 
 impl VM {
-    pub fn set_source(&mut self, source: String) {
-        self.source = source;
+    pub fn with_options(options: VMOptions) -> Self {
+        VM {
+            options,
+            ..Default::default()
+        }
     }
 
-    #[inline(always)]
-    fn span_to_line(&self, span: AstSpan) -> usize {
-        self.source[..span.start].matches('\n').count()
+    pub fn set_source(&mut self, source: String) {
+        self.source = source;
+        self.source_offset = 0;
     }
 
-    fn get_nl_pos(&self, line: usize) -> usize {
-        if line == 0 {
-            return 0;
-        }
-        let mut count = 0;
-        for (i, ch) in self.source.chars().enumerate() {
-            if ch == '\n' {
-                count += 1;
-                if count == line {
-                    return i + 1;
-                }
-            }
+    // Appends code to the source instead of replacing it, so that functions compiled
+    // from earlier sources still report errors at the right place
+    pub fn append_source(&mut self, source: String) {
+        if !self.source.is_empty() {
+            self.source.push('\n');
         }
-        unreachable!();
+        self.source_offset = self.source.len();
+        self.source.push_str(&source);
     }
 
     pub fn compile_error(&mut self, span: AstSpan, message: String) {
-        let line = self.span_to_line(span);
-        let line_str = &self.source.split('\n').nth(line).unwrap();
-        let start = self.get_nl_pos(line);
-
-        self.error = Some(format!(
-            "At Line {}:\n{}\n{}{}\nCompile-time Error:\n    {}",
-            line + 1,
-            line_str,
-            " ".repeat(span.start - start),
-            "^".repeat(span.end - span.start),
-            message
-        ));
+        let span = AstSpan {
+            start: span.start + self.source_offset,
+            end: span.end + self.source_offset,
+        };
+        self.error = Some(GlacierError::Compile(ErrorInfo::new(
+            &self.source,
+            span,
+            message,
+        )));
     }
 
+    // Operands share the span of their instruction, so the last byte read
+    // locates the instruction being executed
     pub fn runtime_error(&mut self, message: String) {
+        let span = self.lines[self.pc - 1];
+        self.error = Some(GlacierError::Runtime(ErrorInfo::new(
+            &self.source,
+            span,
+            message,
+        )));
+    }
+
+    // Stops before the instruction at pc, which the error points at
+    fn stop_error(&mut self, kind: fn(ErrorInfo) -> GlacierError, message: String) {
         let span = self.lines[self.pc];
-        let line = self.span_to_line(span);
-        let line_str = &self.source.split('\n').nth(line).unwrap();
-        let start = self.get_nl_pos(line);
-        self.error = Some(format!(
-            "At Line {}:\n{}\n{}{}\nRuntime Error:\n    {}",
-            line + 1,
-            line_str,
-            " ".repeat(span.start - start),
-            "^".repeat(span.end - span.start),
-            message
-        ));
+        self.error = Some(kind(ErrorInfo::new(&self.source, span, message)));
+    }
+
+    // Flag that makes the VM stop with an Interrupted error, even from another thread
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
     }
 }
 
 // Compilation
 impl VM {
-    fn push_bytecode(&mut self, bytecode: Byte, span: AstSpan) {
+    pub fn push_bytecode(&mut self, bytecode: Byte, span: AstSpan) {
         self.bytecodes.push(bytecode);
         self.lines.push(AstSpan {
-            start: span.start,
-            end: span.end,
+            start: span.start + self.source_offset,
+            end: span.end + self.source_offset,
         });
     }
 
@@ -162,7 +227,14 @@ impl VM {
 
     pub fn end_scope(&mut self) {
         self.current_compiler.scope_depth -= 1;
-        self.current_compiler.count -= self.current_compiler.local_map.pop().unwrap().len();
+        let scope = self.current_compiler.local_map.pop().unwrap();
+        // Slots of captured locals are not reused, as closures may still refer to them
+        if !scope
+            .values()
+            .any(|index| self.current_compiler.captured.contains(index))
+        {
+            self.current_compiler.count -= scope.len();
+        }
     }
 
     pub fn add_local(&mut self, name: String) -> usize {
@@ -174,26 +246,134 @@ impl VM {
         self.current_compiler.local_map[self.current_compiler.scope_depth]
             .insert(name, self.current_compiler.count);
         self.current_compiler.count += 1;
+        self.current_compiler.max_count = self
+            .current_compiler
+            .max_count
+            .max(self.current_compiler.count);
         self.current_compiler.count - 1
     }
 
-    pub fn resolve_local(&mut self, name: String) -> Option<usize> {
-        for i in (0..=self.current_compiler.scope_depth).rev() {
-            if let Some(index) = self.current_compiler.local_map[i].get(&name) {
-                return Some(*index);
+    // Defines a global variable `name` holding a function implemented by the host.
+    // Must not be called while compiling
+    pub fn register_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        let native = Value::NativeFunction(Rc::new(NativeFunction {
+            name: name.to_string(),
+            arity,
+            function: Box::new(function),
+        }));
+
+        let index = self.add_local(name.to_string());
+        while self.stack.len() <= index {
+            self.stack.push(self.heap.alloc(Value::Null));
+        }
+        self.stack[index] = self.heap.alloc(native);
+    }
+
+    // Reserves a slot in the current scope that no variable name can refer to
+    pub fn add_hidden_local(&mut self) -> usize {
+        let index = self.current_compiler.count;
+        // Not a valid identifier, and unique since count only grows while the scope is open
+        self.current_compiler.local_map[self.current_compiler.scope_depth]
+            .insert(format!(" {index}"), index);
+        self.current_compiler.count += 1;
+        self.current_compiler.max_count = self
+            .current_compiler
+            .max_count
+            .max(self.current_compiler.count);
+        index
+    }
+
+    // Where an assignment to `name` stores its value: (replace bytecode, load bytecode, index)
+    // Assigning to an existing variable of an enclosing function or the top level from inside a
+    // function updates it, otherwise a local is declared
+    pub fn assignment_target(&mut self, name: String) -> (Byte, Byte, usize) {
+        if self.resolve_local(name.clone()).is_none() {
+            if let Some(index) = self.resolve_upvalue(name.clone()) {
+                return (REPLACE_UPVALUE, LOAD_UPVALUE, index);
+            }
+            if let Some(index) = self.resolve_global(name.clone()) {
+                return (REPLACE_GLOBAL, LOAD_GLOBAL, index);
             }
         }
-        None
+        (REPLACE, LOAD_LOCAL, self.add_local(name))
+    }
+
+    pub fn resolve_local(&mut self, name: String) -> Option<usize> {
+        self.current_compiler.resolve(&name)
+    }
+
+    // Top level variables, visible from inside functions. Functions use their slot, so like
+    // captured locals it is not reused once its scope ends
+    pub fn resolve_global(&mut self, name: String) -> Option<usize> {
+        let top = self.enclosing_compilers.first_mut()?;
+        let index = top.resolve(&name)?;
+        top.captured.insert(index);
+        Some(index)
+    }
+
+    // Locals of enclosing functions, captured by the function being compiled
+    pub fn resolve_upvalue(&mut self, name: String) -> Option<usize> {
+        self.resolve_upvalue_at(self.enclosing_compilers.len(), &name)
+    }
+
+    fn compiler_at(&mut self, level: usize) -> &mut Compiler {
+        if level == self.enclosing_compilers.len() {
+            &mut self.current_compiler
+        } else {
+            &mut self.enclosing_compilers[level]
+        }
+    }
+
+    fn resolve_upvalue_at(&mut self, level: usize, name: &str) -> Option<usize> {
+        // Level 0 is the top level, whose variables are globals rather than upvalues
+        if level < 2 {
+            return None;
+        }
+
+        let upvalue = if let Some(index) = self.compiler_at(level - 1).resolve(name) {
+            self.compiler_at(level - 1).captured.insert(index);
+            (true, index)
+        } else {
+            (false, self.resolve_upvalue_at(level - 1, name)?)
+        };
+
+        let compiler = self.compiler_at(level);
+        if let Some(index) = compiler.upvalues.iter().position(|u| *u == upvalue) {
+            return Some(index);
+        }
+        compiler.upvalues.push(upvalue);
+        Some(compiler.upvalues.len() - 1)
     }
 
     pub fn compile(&mut self, program: &Program) {
-        self.lines.clear();
-        self.bytecodes.clear();
+        // Code from previous compilations is kept, since functions defined there may still be called
+        self.entry = self.bytecodes.len();
         while self.current_compiler.local_map.len() > 1 {
             self.current_compiler.local_map.pop();
         }
         self.current_compiler.count = self.current_compiler.local_map[0].len();
-        self.compile_program(program);
+        let globals = self.current_compiler.local_map[0].clone();
+        // Constant folding evaluates operators on the heap
+        let _heap = self.heap.enter();
+
+        if !self.compile_program(program) {
+            self.bytecodes.truncate(self.entry);
+            self.lines.truncate(self.entry);
+            if !self.enclosing_compilers.is_empty() {
+                self.current_compiler = self.enclosing_compilers.swap_remove(0);
+                self.enclosing_compilers.clear();
+            }
+            while self.current_compiler.local_map.len() > 1 {
+                self.current_compiler.local_map.pop();
+            }
+            self.current_compiler.scope_depth = 0;
+            self.current_compiler.local_map[0] = globals;
+            self.break_jump_patches.clear();
+            self.next_jump_patches.clear();
+        }
     }
 
     pub fn compile_program(&mut self, program: &Program) -> bool {
@@ -252,7 +432,15 @@ impl VM {
                 self.push_bytecode(0, b.pos);
             }
             Statement::PointerAssign(ptr) => {
-                if !self.compile_expression(&ptr.ptr) {
+                if let Expression::Index(indexing) = &ptr.ptr {
+                    if !self.compile_expression(&indexing.callee) {
+                        return false;
+                    }
+                    if !self.compile_expression(&indexing.index) {
+                        return false;
+                    }
+                    self.push_bytecode(GET_OR_INSERT, indexing.pos);
+                } else if !self.compile_expression(&ptr.ptr) {
                     return false;
                 }
 
@@ -261,23 +449,130 @@ impl VM {
                 }
 
                 self.push_bytecode(SET_IN_PLACE, ptr.pos);
+                // The assigned value is left on the stack, like that of an expression statement
+                self.push_bytecode(POP_LAST, ptr.pos);
+            }
+            Statement::Return(r) => {
+                if self.enclosing_compilers.is_empty() {
+                    self.compile_error(
+                        r.pos,
+                        "Return statement outside of function is not allowed".to_string(),
+                    );
+                    return false;
+                }
+
+                if let Some(value) = &r.value {
+                    if !self.compile_expression(value) {
+                        return false;
+                    }
+                } else {
+                    self.push_bytecode(LOAD_CONST, r.pos);
+                    self.push_bytecode(NULL_CONSTANT as Byte, r.pos);
+                }
+                self.push_bytecode(RETURN, r.pos);
+            }
+            Statement::FunctionDef(def) => {
+                // Declared before the body so that the function can call itself
+                let slot = self.add_local(def.name.to_string());
+
+                if !self.compile_function(def.name, &def.parameters, &def.body, def.pos) {
+                    return false;
+                }
+
+                // Behaves like an assignment statement
+                self.push_bytecode(REPLACE, def.pos);
+                self.push_bytecode(slot as Byte, def.pos);
+                self.push_bytecode(LOAD_LOCAL, def.pos);
+                self.push_bytecode(slot as Byte, def.pos);
+                self.push_bytecode(POP_LAST, def.pos);
             }
         }
         true
     }
 
+    // Compiles the body out of line and pushes the resulting function or closure
+    pub fn compile_function(
+        &mut self,
+        name: &str,
+        parameters: &[&str],
+        body: &Program,
+        pos: AstSpan,
+    ) -> bool {
+        self.push_bytecode(JUMP, pos);
+        let patch_loc = self.bytecodes.len();
+        self.push_bytecode(0, pos);
+
+        let address = self.bytecodes.len();
+
+        let mut compiler = Compiler::default();
+        compiler.local_map.push(HashMap::new());
+        let enclosing = std::mem::replace(&mut self.current_compiler, compiler);
+        self.enclosing_compilers.push(enclosing);
+        let break_jump_patches = std::mem::take(&mut self.break_jump_patches);
+        let next_jump_patches = std::mem::take(&mut self.next_jump_patches);
+
+        for param in parameters {
+            if self.resolve_local(param.to_string()).is_some() {
+                self.compile_error(pos, format!("Duplicate parameter '{param}'"));
+                return false;
+            }
+            self.add_local(param.to_string());
+        }
+
+        if !self.compile_program(body) {
+            return false;
+        }
+
+        // The last expression is the return value
+        if self.bytecodes.last() == Some(&POP_LAST) {
+            self.bytecodes.pop();
+            self.lines.pop();
+        } else {
+            self.push_bytecode(LOAD_CONST, pos);
+            self.push_bytecode(NULL_CONSTANT as Byte, pos);
+        }
+        self.push_bytecode(RETURN, pos);
+
+        let compiler = std::mem::replace(
+            &mut self.current_compiler,
+            self.enclosing_compilers.pop().unwrap(),
+        );
+        self.break_jump_patches = break_jump_patches;
+        self.next_jump_patches = next_jump_patches;
+
+        self.bytecodes[patch_loc] = self.bytecodes.len() as Byte;
+
+        let is_closure = !compiler.upvalues.is_empty();
+        self.constants.push(Value::Function(Rc::new(Function {
+            name: name.to_string(),
+            arity: parameters.len(),
+            address,
+            locals: compiler.max_count,
+            upvalues: compiler.upvalues,
+        })));
+
+        self.push_bytecode(if is_closure { MAKE_CLOSURE } else { LOAD_CONST }, pos);
+        self.push_bytecode(self.constants.len() as Byte - 1, pos);
+
+        true
+    }
+
     pub fn compile_expression(&mut self, expression: &Expression) -> bool {
+        let operator_pos = match expression {
+            Expression::Infix(infix) => Some(infix.pos),
+            Expression::Prefix(prefix) => Some(prefix.pos),
+            _ => None,
+        };
+        if let Some(pos) = operator_pos {
+            if let Some(value) = self.constant_value(expression) {
+                self.load_constant(value, pos);
+                return true;
+            }
+        }
+
         match expression {
             Expression::String_(s) => {
-                if self
-                    .constants
-                    .try_push(Value::String(s.value.to_string()))
-                    .is_err()
-                {
-                    self.compile_error(s.pos, format!("Constant exceeds limit of {CONSTANT_SIZE}"));
-                    return false;
-                }
-
+                self.constants.push(Value::String(s.value.to_string()));
                 self.push_bytecode(LOAD_CONST, s.pos);
                 self.push_bytecode(self.constants.len() as Byte - 1, s.pos);
             }
@@ -285,21 +580,7 @@ impl VM {
             Expression::Int(num) => {
                 let val = num.value.parse::<i64>();
                 if let Ok(val) = val {
-                    let index;
-                    if let Some(k) = self.constant_hash_int.get(&val) {
-                        index = *k;
-                    } else if self.constants.try_push(Value::Int(val)).is_err() {
-                        self.compile_error(
-                            num.pos,
-                            format!("Constant exceeds limit of {CONSTANT_SIZE}"),
-                        );
-                        return false;
-                    } else {
-                        index = self.constants.len() as Byte - 1;
-                        self.constant_hash_int.insert(val, index);
-                    }
-                    self.push_bytecode(LOAD_CONST, num.pos);
-                    self.push_bytecode(index, num.pos);
+                    self.load_constant(Value::Int(val), num.pos);
                 } else {
                     self.compile_error(num.pos, "Integer literal too large".to_string());
                     return false;
@@ -309,13 +590,7 @@ impl VM {
             Expression::Float(num) => {
                 let val = num.value.parse::<f64>();
                 if let Ok(val) = val {
-                    if self.constants.try_push(Value::Float(val)).is_err() {
-                        self.compile_error(
-                            num.pos,
-                            format!("Constant exceeds limit of {CONSTANT_SIZE}"),
-                        );
-                        return false;
-                    }
+                    self.constants.push(Value::Float(val));
                     let index = self.constants.len() as Byte - 1;
                     self.push_bytecode(LOAD_CONST, num.pos);
                     self.push_bytecode(index, num.pos);
@@ -343,11 +618,39 @@ impl VM {
                 self.push_bytecode(a.values.len() as Byte, a.pos);
             }
 
+            Expression::Interpolation(s) => {
+                for x in s.parts.iter() {
+                    if !self.compile_expression(x) {
+                        return false;
+                    }
+                }
+                self.push_bytecode(MAKE_STRING, s.pos);
+                self.push_bytecode(s.parts.len() as Byte, s.pos);
+            }
+
+            Expression::Hash(h) => {
+                for (k, v) in h.pairs.iter() {
+                    if !self.compile_expression(k) {
+                        return false;
+                    }
+                    if !self.compile_expression(v) {
+                        return false;
+                    }
+                }
+                self.push_bytecode(MAKE_HASH, h.pos);
+                self.push_bytecode(h.pairs.len() as Byte, h.pos);
+            }
+
             Expression::GetVar(get) => {
-                let res = self.resolve_local(get.name.to_string());
-                if let Some(index) = res {
+                if let Some(index) = self.resolve_local(get.name.to_string()) {
                     self.push_bytecode(LOAD_LOCAL, get.pos);
                     self.push_bytecode(index as Byte, get.pos);
+                } else if let Some(index) = self.resolve_upvalue(get.name.to_string()) {
+                    self.push_bytecode(LOAD_UPVALUE, get.pos);
+                    self.push_bytecode(index as Byte, get.pos);
+                } else if let Some(index) = self.resolve_global(get.name.to_string()) {
+                    self.push_bytecode(LOAD_GLOBAL, get.pos);
+                    self.push_bytecode(index as Byte, get.pos);
                 } else {
                     self.compile_error(get.pos, format!("Variable '{}' is not defined", get.name));
                     return false;
@@ -355,20 +658,78 @@ impl VM {
             }
 
             Expression::SetVar(var) => {
-                let replace = self.add_local(var.name.to_string());
+                let (replace, load, index) = self.assignment_target(var.name.to_string());
 
                 if !self.compile_expression(&var.value) {
                     return false;
                 }
 
-                self.push_bytecode(REPLACE, var.pos);
-                self.push_bytecode(replace as Byte, var.pos);
-                self.push_bytecode(LOAD_LOCAL, var.pos);
-                self.push_bytecode(replace as Byte, var.pos);
+                self.push_bytecode(replace, var.pos);
+                self.push_bytecode(index as Byte, var.pos);
+                self.push_bytecode(load, var.pos);
+                self.push_bytecode(index as Byte, var.pos);
+            }
+
+            Expression::For(f) => {
+                if !self.compile_expression(&f.iterable) {
+                    return false;
+                }
+
+                self.begin_scope();
+
+                // The iterable and the iteration state live in two consecutive hidden locals
+                let slot = self.add_hidden_local();
+                self.add_hidden_local();
+                self.push_bytecode(ITER_START, f.pos);
+                self.push_bytecode(slot as Byte, f.pos);
+
+                self.break_jump_patches.push(Vec::new());
+                self.next_jump_patches.push(Vec::new());
+
+                let loop_start = self.bytecodes.len();
+
+                self.push_bytecode(FOR_ITER, f.pos);
+                self.push_bytecode(slot as Byte, f.pos);
+                let patch_loc = self.bytecodes.len();
+                self.push_bytecode(0, f.pos);
+
+                let (replace, _, index) = self.assignment_target(f.variable.to_string());
+                self.push_bytecode(replace, f.pos);
+                self.push_bytecode(index as Byte, f.pos);
+
+                if !self.compile_program(&f.body) {
+                    return false;
+                }
+
+                self.push_bytecode(JUMP, f.pos);
+                self.push_bytecode(loop_start as Byte, f.pos);
+
+                self.bytecodes[patch_loc] = self.bytecodes.len() as Byte;
+
+                let list = self.break_jump_patches.pop().unwrap();
+                for i in list {
+                    self.bytecodes[i] = self.bytecodes.len() as Byte;
+                }
+
+                let list = self.next_jump_patches.pop().unwrap();
+                for i in list {
+                    self.bytecodes[i] = loop_start as Byte;
+                }
+
+                self.end_scope();
+
+                self.push_bytecode(LOAD_CONST, f.pos);
+                self.push_bytecode(NULL_CONSTANT as Byte, f.pos);
             }
 
             Expression::Infix(infix) => {
                 match infix.operator {
+                    // A constant left side that was not folded leaves the result to the right side
+                    "&&" | "||" if self.constant_value(&infix.left).is_some() => {
+                        if !self.compile_expression(&infix.right) {
+                            return false;
+                        }
+                    }
                     "&&" => {
                         if !self.compile_expression(&infix.left) {
                             return false;
@@ -454,6 +815,15 @@ impl VM {
                                 self.push_bytecode(BINARY_GE, infix.pos);
                             }
 
+                            ".." => {
+                                self.push_bytecode(MAKE_RANGE, infix.pos);
+                                self.push_bytecode(1, infix.pos);
+                            }
+                            "..." => {
+                                self.push_bytecode(MAKE_RANGE, infix.pos);
+                                self.push_bytecode(0, infix.pos);
+                            }
+
                             _ => {
                                 self.compile_error(
                                     infix.pos,
@@ -500,7 +870,38 @@ impl VM {
                 self.push_bytecode(GET, indexing.pos);
             }
 
+            Expression::Call(call) => {
+                if !self.compile_expression(&call.callee) {
+                    return false;
+                }
+
+                for x in call.arguments.iter() {
+                    if !self.compile_expression(x) {
+                        return false;
+                    }
+                }
+
+                self.push_bytecode(CALL, call.pos);
+                self.push_bytecode(call.arguments.len() as Byte, call.pos);
+            }
+
+            Expression::Function(f) => {
+                if !self.compile_function("anonymous", &f.parameters, &f.body, f.pos) {
+                    return false;
+                }
+            }
+
             Expression::If(iff) => {
+                // Only the branch taken is kept, the other is still checked for errors
+                if let Some(cond) = self.constant_value(&iff.cond) {
+                    let (taken, dropped) = if cond.is_truthy() {
+                        (&iff.body, &iff.other)
+                    } else {
+                        (&iff.other, &iff.body)
+                    };
+                    return self.compile_discarded(dropped) && self.compile_block(taken, iff.pos);
+                }
+
                 // Compile Condition
                 if !self.compile_expression(&iff.cond) {
                     return false;
@@ -511,25 +912,9 @@ impl VM {
                 let patch_loc = self.bytecodes.len();
                 self.push_bytecode(0, iff.pos);
 
-                if !iff.body.is_empty() {
-                    // Compile then block
-                    self.begin_scope();
-                    if !self.compile_program(&iff.body) {
-                        return false;
-                    }
-                    self.end_scope();
-
-                    // If there is a result, don't pop it
-                    if self.bytecodes.last() == Some(&POP_LAST) {
-                        self.bytecodes.pop();
-                        self.lines.pop();
-                    } else {
-                        self.push_bytecode(LOAD_CONST, iff.pos);
-                        self.push_bytecode(NULL_CONSTANT as Byte, iff.pos);
-                    }
-                } else {
-                    self.push_bytecode(LOAD_CONST, iff.pos);
-                    self.push_bytecode(NULL_CONSTANT as Byte, iff.pos);
+                // Compile then block
+                if !self.compile_block(&iff.body, iff.pos) {
+                    return false;
                 }
 
                 // Jump to end
@@ -540,50 +925,44 @@ impl VM {
                 // Patch jump 1
                 self.bytecodes[patch_loc] = self.bytecodes.len() as Byte;
 
-                if iff.other.is_empty() {
-                    self.push_bytecode(LOAD_CONST, iff.pos);
-                    self.push_bytecode(NULL_CONSTANT as Byte, iff.pos);
-
-                    self.bytecodes[patch_loc_2] = self.bytecodes.len() as Byte;
-                } else {
-                    if !iff.other.is_empty() {
-                        // Compile else block
-                        self.begin_scope();
-                        if !self.compile_program(&iff.other) {
-                            return false;
-                        }
-                        self.end_scope();
-
-                        // If there is a result, don't pop it
-                        if self.bytecodes.last() == Some(&POP_LAST) {
-                            self.bytecodes.pop();
-                            self.lines.pop();
-                        } else {
-                            self.push_bytecode(LOAD_CONST, iff.pos);
-                            self.push_bytecode(NULL_CONSTANT as Byte, iff.pos);
-                        }
-                    } else {
-                        self.push_bytecode(LOAD_CONST, iff.pos);
-                        self.push_bytecode(NULL_CONSTANT as Byte, iff.pos);
-                    }
-
-                    self.bytecodes[patch_loc_2] = self.bytecodes.len() as Byte;
+                // Compile else block
+                if !self.compile_block(&iff.other, iff.pos) {
+                    return false;
                 }
+
+                self.bytecodes[patch_loc_2] = self.bytecodes.len() as Byte;
             }
 
             Expression::While(w) => {
+                let cond = self.constant_value(&w.cond);
+
                 self.break_jump_patches.push(Vec::new());
                 self.next_jump_patches.push(Vec::new());
 
+                // A loop that never runs is only checked for errors
+                if cond.as_ref().is_some_and(|c| !c.is_truthy()) {
+                    if !self.compile_discarded(&w.body) {
+                        return false;
+                    }
+                    self.break_jump_patches.pop();
+                    self.next_jump_patches.pop();
+                    self.load_constant(Value::Null, w.pos);
+                    return true;
+                }
+
                 let loop_start = self.bytecodes.len();
 
-                if !self.compile_expression(&w.cond) {
-                    return false;
-                }
+                // Always true conditions are not checked, the loop only ends with break
+                let mut patch_loc = None;
+                if cond.is_none() {
+                    if !self.compile_expression(&w.cond) {
+                        return false;
+                    }
 
-                self.push_bytecode(JUMP_IF_FALSE, w.pos);
-                let patch_loc = self.bytecodes.len();
-                self.push_bytecode(0, w.pos);
+                    self.push_bytecode(JUMP_IF_FALSE, w.pos);
+                    patch_loc = Some(self.bytecodes.len());
+                    self.push_bytecode(0, w.pos);
+                }
 
                 self.begin_scope();
                 if !self.compile_program(&w.body) {
@@ -594,7 +973,9 @@ impl VM {
                 self.push_bytecode(JUMP, w.pos);
                 self.push_bytecode(loop_start as Byte, w.pos);
 
-                self.bytecodes[patch_loc] = self.bytecodes.len() as Byte;
+                if let Some(patch_loc) = patch_loc {
+                    self.bytecodes[patch_loc] = self.bytecodes.len() as Byte;
+                }
 
                 let list = self.break_jump_patches.pop().unwrap();
                 for i in list {
@@ -611,32 +992,78 @@ impl VM {
             }
 
             Expression::Do(d) => {
-                self.begin_scope();
-                if !self.compile_program(&d.body) {
+                if !self.compile_block(&d.body, d.pos) {
                     return false;
                 }
-                self.end_scope();
-                if self.bytecodes.last() == Some(&POP_LAST) {
-                    self.bytecodes.pop();
-                    self.lines.pop();
-                } else {
-                    self.push_bytecode(LOAD_CONST, d.pos);
-                    self.push_bytecode(NULL_CONSTANT as Byte, d.pos);
-                }
             }
         }
 
         true
     }
 
+    // Compiles a block in its own scope, leaving the value of its last expression or null
+    fn compile_block(&mut self, body: &Program, pos: AstSpan) -> bool {
+        if body.is_empty() {
+            self.load_constant(Value::Null, pos);
+            return true;
+        }
+
+        self.begin_scope();
+        if !self.compile_program(body) {
+            return false;
+        }
+        self.end_scope();
+
+        // If there is a result, don't pop it
+        if self.bytecodes.last() == Some(&POP_LAST) {
+            self.bytecodes.pop();
+            self.lines.pop();
+        } else {
+            self.load_constant(Value::Null, pos);
+        }
+        true
+    }
+
+    // Compiles code that can never run, only to report its errors, then throws it away
+    fn compile_discarded(&mut self, body: &Program) -> bool {
+        let len = self.bytecodes.len();
+        let constants = self.constants.len();
+
+        self.begin_scope();
+        if !self.compile_program(body) {
+            return false;
+        }
+        self.end_scope();
+
+        self.bytecodes.truncate(len);
+        self.lines.truncate(len);
+        // Along with the functions defined in it, which the verifier would otherwise follow
+        self.constants.truncate(constants);
+        self.constant_hash_int
+            .retain(|_, index| (*index as usize) < constants);
+        for patches in self
+            .break_jump_patches
+            .iter_mut()
+            .chain(self.next_jump_patches.iter_mut())
+        {
+            patches.retain(|&patch| patch < len);
+        }
+        true
+    }
+
     pub fn optimize(&mut self) {
-        let mut i = 0;
+        let targets = self.jump_targets();
+        let mut i = self.entry;
         while i < self.bytecodes.len() {
             let b = self.bytecodes[i];
             if b == LOAD_LOCAL || b == LOAD_CONST {
                 // LOAD xxxx POP
                 // This has no effect at all, except at end of file.
-                if i + 2 < self.bytecodes.len() - 1 && self.bytecodes[i + 2] == POP_LAST {
+                // A jump to the POP still needs it, to pop what the other path pushed
+                if i + 2 < self.bytecodes.len() - 1
+                    && self.bytecodes[i + 2] == POP_LAST
+                    && !targets.contains(&(i + 2))
+                {
                     for j in i..=i + 2 {
                         self.bytecodes[j] = NOOP;
                     }
@@ -648,6 +1075,131 @@ impl VM {
             i += 1;
             i += operands(b);
         }
+
+        self.compact();
+        if self.options.superinstructions {
+            self.fuse();
+            self.compact();
+        }
+    }
+
+    // Puts superinstructions in place of the sequences they stand for, in the code compiled
+    // last. Sequences that are jumped into are kept, as the jump would land inside the new one
+    fn fuse(&mut self) {
+        let targets = self.jump_targets();
+        let mut i = self.entry;
+        while i < self.bytecodes.len() {
+            let instruction = self.bytecodes[i];
+            // Fused instruction, where the operator of the sequence is, and the sequence's length
+            let (fused, operator, length): (Vec<Byte>, usize, usize) = match self.bytecodes[i..] {
+                [LOAD_LOCAL, a, LOAD_CONST, c, BINARY_ADD, REPLACE, r, ..] if a == r => {
+                    (vec![INCREMENT_LOCAL, a, c], i + 4, 7)
+                }
+                [LOAD_LOCAL, a, LOAD_CONST, c, op, JUMP_IF_FALSE, t, ..]
+                    if (BINARY_EQ..=BINARY_GE).contains(&op) =>
+                {
+                    (vec![COMPARE_LOCAL_JUMP, a, c, op, t], i + 4, 7)
+                }
+                [LOAD_LOCAL, a, LOAD_LOCAL, b, BINARY_ADD, ..] => {
+                    (vec![ADD_LOCALS, a, b], i + 4, 5)
+                }
+                _ => {
+                    i += 1 + operands(instruction);
+                    continue;
+                }
+            };
+
+            // Where the instructions after the first one start
+            let inner = [i + 2, i + 4, i + 5];
+            if inner.iter().any(|j| *j < i + length && targets.contains(j)) {
+                i += 1 + operands(instruction);
+                continue;
+            }
+
+            // Errors are reported where the operator is
+            let span = self.lines[operator];
+            for j in i..i + length {
+                self.bytecodes[j] = fused.get(j - i).copied().unwrap_or(NOOP);
+                self.lines[j] = span;
+            }
+            i += length;
+        }
+    }
+
+    // Removes the NOOPs from the code compiled last, so that they are not dispatched.
+    // Jump targets and function addresses into that code move along with it
+    fn compact(&mut self) {
+        let entry = self.entry;
+        let len = self.bytecodes.len();
+        // New address of each old one, or of the next instruction kept for removed ones
+        let mut moved = vec![0; len - entry + 1];
+
+        let mut to = entry;
+        let mut i = entry;
+        while i < len {
+            let b = self.bytecodes[i];
+            if b == NOOP {
+                moved[i - entry] = to;
+                i += 1;
+                continue;
+            }
+            for _ in 0..=operands(b) {
+                moved[i - entry] = to;
+                self.bytecodes[to] = self.bytecodes[i];
+                self.lines[to] = self.lines[i];
+                to += 1;
+                i += 1;
+            }
+        }
+        moved[len - entry] = to;
+        self.bytecodes.truncate(to);
+        self.lines.truncate(to);
+
+        let mut i = entry;
+        while i < self.bytecodes.len() {
+            let b = self.bytecodes[i];
+            let target = match b {
+                JUMP | JUMP_IF_FALSE | JUMP_IF_FALSE_NO_POP => i + 1,
+                FOR_ITER => i + 2,
+                COMPARE_LOCAL_JUMP => i + 4,
+                _ => 0,
+            };
+            if target != 0 {
+                self.bytecodes[target] = moved[self.bytecodes[target] as usize - entry] as Byte;
+            }
+            i += 1 + operands(b);
+        }
+
+        for constant in self.constants.iter_mut() {
+            if let Value::Function(f) = constant {
+                if f.address >= entry {
+                    Rc::make_mut(f).address = moved[f.address - entry];
+                }
+            }
+        }
+    }
+
+    // Addresses jumped to from the code compiled last
+    fn jump_targets(&self) -> HashSet<usize> {
+        let mut targets = HashSet::new();
+        let mut i = self.entry;
+        while i < self.bytecodes.len() {
+            let b = self.bytecodes[i];
+            match b {
+                JUMP | JUMP_IF_FALSE | JUMP_IF_FALSE_NO_POP => {
+                    targets.insert(self.bytecodes[i + 1] as usize);
+                }
+                FOR_ITER => {
+                    targets.insert(self.bytecodes[i + 2] as usize);
+                }
+                COMPARE_LOCAL_JUMP => {
+                    targets.insert(self.bytecodes[i + 4] as usize);
+                }
+                _ => (),
+            }
+            i += 1 + operands(b);
+        }
+        targets
     }
 
     pub fn disassemble(&self) -> String {
@@ -664,7 +1216,7 @@ impl VM {
 
             let mut args: Vec<String> = vec![];
             match byte {
-                LOAD_CONST => {
+                LOAD_CONST | MAKE_CLOSURE => {
                     pc += 1;
                     let address = self.bytecodes[pc] as usize;
                     args.push(format!(
@@ -674,12 +1226,39 @@ impl VM {
                     ));
                 }
 
-                LOAD_LOCAL | MAKE_ARRAY | REPLACE | JUMP_IF_FALSE | JUMP_IF_FALSE_NO_POP | JUMP => {
+                LOAD_LOCAL | LOAD_GLOBAL | LOAD_UPVALUE | MAKE_ARRAY | MAKE_STRING | MAKE_HASH
+                | MAKE_RANGE | REPLACE | REPLACE_GLOBAL | REPLACE_UPVALUE | JUMP_IF_FALSE
+                | JUMP_IF_FALSE_NO_POP | JUMP | CALL | ITER_START => {
                     pc += 1;
                     let address = self.bytecodes[pc] as usize;
                     args.push(format!("{address:04x}"));
                 }
 
+                INCREMENT_LOCAL | COMPARE_LOCAL_JUMP => {
+                    let local = self.bytecodes[pc + 1] as usize;
+                    let constant = self.bytecodes[pc + 2] as usize;
+                    args.push(format!("{local:04x}"));
+                    args.push(format!(
+                        "{:04x} ({})",
+                        constant,
+                        self.constants[constant].debug_format()
+                    ));
+                    pc += 2;
+                    if byte == COMPARE_LOCAL_JUMP {
+                        args.push(bytecode_name(self.bytecodes[pc + 1]).to_string());
+                        args.push(format!("{:04x}", self.bytecodes[pc + 2]));
+                        pc += 2;
+                    }
+                }
+
+                FOR_ITER | ADD_LOCALS => {
+                    for _ in 0..2 {
+                        pc += 1;
+                        let address = self.bytecodes[pc] as usize;
+                        args.push(format!("{address:04x}"));
+                    }
+                }
+
                 _ => (),
             }
 
@@ -697,6 +1276,46 @@ impl VM {
     }
 }
 
+// Outcome of one of the BINARY_ comparisons, agreeing with is_equal and binary_lt and the
+// others without allocating the result
+fn compare(left: &Value, right: &Value, comparison: Byte) -> Result<bool, String> {
+    use std::cmp::Ordering;
+
+    match comparison {
+        BINARY_EQ => return Ok(left.is_equal(right)),
+        BINARY_NE => return Ok(!left.is_equal(right)),
+        _ => (),
+    }
+
+    let ordering = match (left, right) {
+        (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+        (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+        (Value::Int(a), Value::Float(b)) => (*a as f64).partial_cmp(b),
+        (Value::Float(a), Value::Int(b)) => a.partial_cmp(&(*b as f64)),
+        (Value::String(a), Value::String(b)) => a.partial_cmp(b),
+        _ => {
+            let operator = match comparison {
+                BINARY_LT => "<",
+                BINARY_LE => "<=",
+                BINARY_GT => ">",
+                _ => ">=",
+            };
+            return Err(format!(
+                "Unsupported Binary operation: {} {operator} {}",
+                left.type_name(),
+                right.type_name()
+            ));
+        }
+    };
+    // Unordered floats, that is NaN, fail every comparison
+    Ok(match comparison {
+        BINARY_LT => ordering == Some(Ordering::Less),
+        BINARY_LE => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        BINARY_GT => ordering == Some(Ordering::Greater),
+        _ => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+    })
+}
+
 // Execution
 impl VM {
     #[inline(always)]
@@ -705,23 +1324,99 @@ impl VM {
         self.bytecodes[self.pc - 1]
     }
 
+    fn capture_upvalue(&mut self, index: usize) -> Rc<RefCell<Upvalue>> {
+        for upvalue in self.open_upvalues.iter() {
+            if *upvalue.borrow() == Upvalue::Open(index) {
+                return upvalue.clone();
+            }
+        }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(index)));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    // Replaces the native and its `argc` arguments on the stack with its result.
+    // Arguments stay on the stack during the call, so whatever they reference is still
+    // alive, and the result is on the stack before the next collection can run
+    fn call_native(&mut self, native: Rc<NativeFunction>, argc: usize) -> Result<(), String> {
+        if native.arity != argc {
+            return Err(format!(
+                "Function {} expects {} arguments, got {}",
+                native.name, native.arity, argc
+            ));
+        }
+
+        let start = self.stack.len() - argc;
+        let args: Vec<Value> = self.stack[start..]
+            .iter()
+            .map(|v| unsafe { &**v }.clone())
+            .collect();
+        let result = (native.function)(&args)?;
+
+        self.stack.truncate(start - 1);
+        self.stack.push(alloc_new_value(result));
+        Ok(())
+    }
+
+    // Moves the values of captured variables at or above `from` off the stack
+    fn close_upvalues(&mut self, from: usize) {
+        let stack = &self.stack;
+        let heap = &self.heap;
+        self.open_upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+            match *upvalue {
+                Upvalue::Open(index) if index >= from => {
+                    // Leaves the stack, which is only scanned at the start and end of marking
+                    heap.shade_stored(stack[index]);
+                    *upvalue = Upvalue::Closed(stack[index]);
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
     pub fn execute(&mut self) {
+        let _heap = self.heap.enter();
+
+        // Frames left over by a runtime error
+        self.close_upvalues(0);
         while self.stack.len() > self.current_compiler.count {
             self.stack.pop();
         }
+        // Variables of the top level get their slots before any temporary is pushed,
+        // like the locals of a call. Each slot gets its own null, since SET_IN_PLACE may
+        // write into it
+        while self.stack.len() < self.global_slots() {
+            self.stack.push(self.heap.alloc(Value::Null));
+        }
         self.last_popped = None;
-        self.pc = 0;
-
-        let mut iteration = 0;
+        self.frames.clear();
+        self.base = 0;
+        self.pc = self.entry;
+        let mut fuel = self.options.max_instructions;
 
         while self.pc < self.bytecodes.len() {
-            if iteration == GC_TRIGGER {
-                self.gc_recollect();
-                iteration = 0;
+            if let Some(f) = fuel.as_mut() {
+                if *f == 0 {
+                    let max = self.options.max_instructions.unwrap();
+                    self.stop_error(
+                        GlacierError::BudgetExhausted,
+                        format!("Instruction budget of {max} exhausted"),
+                    );
+                    return;
+                }
+                *f -= 1;
             }
-            if ALL_ALLOCATIONS.lock().unwrap().len() - unsafe { LAST_ALLOCATED } >= GC_FORCE_COLLECT
-            {
-                self.gc_recollect();
+
+            if self.interrupt.load(Ordering::Relaxed) {
+                self.interrupt.store(false, Ordering::Relaxed);
+                self.stop_error(GlacierError::Interrupted, "Interrupted".to_string());
+                return;
+            }
+
+            if !self.gc_step() {
+                return;
             }
 
             unsafe {
@@ -741,36 +1436,100 @@ impl VM {
 
                     REPLACE => {
                         let index = self.read_bytecode();
+                        let index = self.base + index as usize;
                         let v = self.stack.pop().unwrap();
-                        while self.stack.len() <= index as usize {
-                            self.stack
-                                .push(&mut self.constants[NULL_CONSTANT] as *mut Value);
+                        while self.stack.len() <= index {
+                            self.stack.push(self.heap.alloc(Value::Null));
+                        }
+                        self.stack[index] = (*v).shallow_copy();
+                        self.heap.shade_stored(self.stack[index]);
+                    }
+
+                    REPLACE_GLOBAL => {
+                        let index = self.read_bytecode() as usize;
+                        let v = self.stack.pop().unwrap();
+                        self.stack[index] = (*v).shallow_copy();
+                        self.heap.shade_stored(self.stack[index]);
+                    }
+
+                    REPLACE_UPVALUE => {
+                        let index = self.read_bytecode() as usize;
+                        let v = (*self.stack.pop().unwrap()).shallow_copy();
+                        self.heap.shade_stored(v);
+                        if let Value::Closure(c) = &*self.stack[self.base - 1] {
+                            let mut upvalue = c.upvalues[index].borrow_mut();
+                            match *upvalue {
+                                Upvalue::Open(i) => self.stack[i] = v,
+                                Upvalue::Closed(_) => *upvalue = Upvalue::Closed(v),
+                            }
+                        } else {
+                            self.runtime_error("Upvalue used outside of a closure".to_string());
+                            return;
                         }
-                        self.stack[index as usize] = (*v).shallow_copy();
                     }
 
                     SET_IN_PLACE => {
                         let v = self.stack.pop().unwrap();
                         let p = self.stack.pop().unwrap();
                         (*v).regular_copy_to(p);
+                        self.heap.barrier(p);
                         self.stack.push(v);
                     }
 
                     LOAD_CONST => {
                         let index = self.read_bytecode();
-                        if self
-                            .stack
-                            .try_push(alloc_new_value(self.constants[index as usize].clone()))
-                            .is_err()
-                        {
+                        if self.stack.len() >= self.options.stack_size {
                             self.runtime_error("Stack overflow".to_string());
                             return;
                         }
+                        self.stack
+                            .push(alloc_new_value(self.constants[index as usize].clone()));
                     }
 
                     LOAD_LOCAL => {
                         let index = self.read_bytecode();
-                        self.stack.push(*self.stack.get(index as usize).unwrap());
+                        self.stack.push(self.stack[self.base + index as usize]);
+                    }
+
+                    LOAD_GLOBAL => {
+                        let index = self.read_bytecode();
+                        self.stack.push(self.stack[index as usize]);
+                    }
+
+                    LOAD_UPVALUE => {
+                        let index = self.read_bytecode() as usize;
+                        if let Value::Closure(c) = &*self.stack[self.base - 1] {
+                            let v = match *c.upvalues[index].borrow() {
+                                Upvalue::Open(i) => self.stack[i],
+                                Upvalue::Closed(v) => v,
+                            };
+                            self.stack.push(v);
+                        } else {
+                            self.runtime_error("Upvalue used outside of a closure".to_string());
+                            return;
+                        }
+                    }
+
+                    MAKE_CLOSURE => {
+                        let index = self.read_bytecode() as usize;
+                        if let Value::Function(f) = self.constants[index].clone() {
+                            let mut upvalues = Vec::with_capacity(f.upvalues.len());
+                            for (is_local, i) in f.upvalues.iter() {
+                                if *is_local {
+                                    upvalues.push(self.capture_upvalue(self.base + i));
+                                } else if let Value::Closure(c) = &*self.stack[self.base - 1] {
+                                    upvalues.push(c.upvalues[*i].clone());
+                                }
+                            }
+                            self.stack
+                                .push(alloc_new_value(Value::Closure(Rc::new(Closure {
+                                    function: f,
+                                    upvalues,
+                                }))));
+                        } else {
+                            self.runtime_error("Closure must be made from a function".to_string());
+                            return;
+                        }
                     }
 
                     MAKE_ARRAY => {
@@ -779,8 +1538,92 @@ impl VM {
                         for _ in 0..length {
                             array.push(self.stack.pop().unwrap());
                         }
+                        self.stack.push(alloc_new_value(Value::Array(array)));
+                    }
+
+                    MAKE_STRING => {
+                        let length = self.read_bytecode() as usize;
+                        let start = self.stack.len() - length;
+                        let mut s = String::new();
+                        for x in self.stack.drain(start..) {
+                            s.push_str(&(*x).print_format());
+                        }
+                        self.stack.push(alloc_new_value(Value::String(s)));
+                    }
+
+                    MAKE_HASH => {
+                        let length = self.read_bytecode() as usize;
+                        let start = self.stack.len() - length * 2;
+                        let mut hash = IndexMap::with_capacity(length);
+                        for i in 0..length {
+                            let key = &*self.stack[start + i * 2];
+                            match key.hash_key() {
+                                Ok(key) => {
+                                    hash.insert(key, self.stack[start + i * 2 + 1]);
+                                }
+                                Err(e) => {
+                                    self.runtime_error(e);
+                                    return;
+                                }
+                            }
+                        }
+                        self.stack.truncate(start);
                         self.stack
-                            .push_unchecked(alloc_new_value(Value::Array(array)));
+                            .push(alloc_new_value(Value::Hash(Box::new(hash))));
+                    }
+
+                    MAKE_RANGE => {
+                        let inclusive = self.read_bytecode() != 0;
+                        let right = &*self.stack.pop().unwrap();
+                        let left = &*self.stack.pop().unwrap();
+                        let res = left.make_range(right, inclusive);
+                        if let BinOpResult::Ok(res) = res {
+                            self.stack.push(res);
+                        } else if let BinOpResult::Error(e) = res {
+                            self.runtime_error(e);
+                            return;
+                        } else {
+                            self.runtime_error(format!(
+                                "Unsupported Binary operation: {} {} {}",
+                                left.type_name(),
+                                if inclusive { ".." } else { "..." },
+                                right.type_name()
+                            ));
+                            return;
+                        }
+                    }
+
+                    ITER_START => {
+                        let index = self.base + self.read_bytecode() as usize;
+                        let v = self.stack.pop().unwrap();
+                        while self.stack.len() <= index + 1 {
+                            self.stack.push(self.heap.alloc(Value::Null));
+                        }
+                        // A copy, so that the body cannot change what is being iterated
+                        self.stack[index] = alloc_new_value((*v).clone());
+                        self.stack[index + 1] = alloc_new_value(Value::Int(0));
+                    }
+
+                    FOR_ITER => {
+                        let index = self.base + self.read_bytecode() as usize;
+                        let address = self.read_bytecode();
+                        let state = self.stack[index + 1];
+                        if let Value::Int(i) = *state {
+                            match (*self.stack[index]).iterate(i) {
+                                Ok(Some((item, next))) => {
+                                    // The state is private to the loop, so it is updated in place
+                                    *state = Value::Int(next);
+                                    self.stack.push(item);
+                                }
+                                Ok(None) => {
+                                    self.pc = address as usize;
+                                }
+                                Err(e) => {
+                                    self.runtime_error(e);
+                                    return;
+                                }
+                            }
+                        }
                     }
 
                     JUMP_IF_FALSE => {
@@ -802,14 +1645,16 @@ impl VM {
                         self.pc = address as usize;
                     }
 
-                    DEBUG_PRINT => {
-                        let value = self.stack.pop().unwrap();
-                        println!("{}", (*value).debug_format());
-                    }
-
-                    ECHO_PRINT => {
-                        let value = self.stack.pop().unwrap();
-                        println!("{}", (*value).print_format());
+                    DEBUG_PRINT | ECHO_PRINT => {
+                        let value = &*self.stack.pop().unwrap();
+                        let line = match bc {
+                            DEBUG_PRINT => value.debug_format(),
+                            _ => value.print_format(),
+                        };
+                        if let Err(e) = self.output.print_line(&line) {
+                            self.runtime_error(format!("Cannot write output: {e}"));
+                            return;
+                        }
                     }
 
                     GET => {
@@ -825,6 +1670,78 @@ impl VM {
                         self.stack.push(res.unwrap());
                     }
 
+                    GET_OR_INSERT => {
+                        let index = self.stack.pop().unwrap();
+                        let callee = self.stack.pop().unwrap();
+
+                        match (*callee).get_or_insert_element(index) {
+                            Ok(res) => self.stack.push(res),
+                            Err(e) => {
+                                self.runtime_error(e);
+                                return;
+                            }
+                        }
+                    }
+
+                    CALL => {
+                        let argc = self.read_bytecode() as usize;
+                        let callee = &*self.stack[self.stack.len() - argc - 1];
+                        let function = match callee {
+                            Value::Function(f) => Some(f),
+                            Value::Closure(c) => Some(&c.function),
+                            Value::NativeFunction(n) => {
+                                if let Err(e) = self.call_native(n.clone(), argc) {
+                                    self.runtime_error(e);
+                                    return;
+                                }
+                                continue;
+                            }
+                            _ => None,
+                        };
+                        if let Some(f) = function {
+                            if f.arity != argc {
+                                self.runtime_error(format!(
+                                    "Function {} expects {} arguments, got {}",
+                                    f.name, f.arity, argc
+                                ));
+                                return;
+                            }
+
+                            let base = self.stack.len() - argc;
+                            if self.frames.len() >= self.options.max_frames
+                                || base + f.locals >= self.options.stack_size
+                            {
+                                self.runtime_error("Stack overflow".to_string());
+                                return;
+                            }
+
+                            while self.stack.len() < base + f.locals {
+                                self.stack.push(self.heap.alloc(Value::Null));
+                            }
+
+                            self.frames.push(CallFrame {
+                                return_address: self.pc,
+                                base: self.base,
+                            });
+                            self.base = base;
+                            self.pc = f.address;
+                        } else {
+                            self.runtime_error(format!("Cannot call type {}", callee.type_name()));
+                            return;
+                        }
+                    }
+
+                    RETURN => {
+                        let value = self.stack.pop().unwrap();
+                        let frame = self.frames.pop().unwrap();
+                        self.close_upvalues(self.base);
+                        // Also discards the function itself, which sits just below the arguments
+                        self.stack.truncate(self.base - 1);
+                        self.stack.push(value);
+                        self.pc = frame.return_address;
+                        self.base = frame.base;
+                    }
+
                     // Prefix operators
                     UNARY_NEG => {
                         let value = &*self.stack.pop().unwrap();
@@ -838,12 +1755,12 @@ impl VM {
                             }
                             Value::Int(i) => {
                                 // We just popped an element, so there should be an empty space on the stack.
-                                self.stack.push_unchecked(alloc_new_value(Value::Int(
+                                self.stack.push(alloc_new_value(Value::Int(
                                     i.saturating_neg(),
                                 )));
                             }
                             Value::Float(f) => {
-                                self.stack.push_unchecked(alloc_new_value(Value::Float(-*f)));
+                                self.stack.push(alloc_new_value(Value::Float(-*f)));
                             }
                             _ => {
                                 self.runtime_error(format!(
@@ -858,7 +1775,7 @@ impl VM {
                     UNARY_NOT => {
                         let value = &*self.stack.pop().unwrap();
                         self.stack
-                            .push_unchecked(alloc_new_value(Value::Bool(!value.is_truthy())));
+                            .push(alloc_new_value(Value::Bool(!value.is_truthy())));
                     }
 
                     // Infix operators
@@ -867,7 +1784,7 @@ impl VM {
                         let left = &*self.stack.pop().unwrap();
                         let res = left.binary_add(right);
                         if let BinOpResult::Ok(res) = res {
-                            self.stack.push_unchecked(res);
+                            self.stack.push(res);
                         } else if let BinOpResult::Error(e) = res {
                             self.runtime_error(e);
                             return;
@@ -886,7 +1803,7 @@ impl VM {
                         let left = &*self.stack.pop().unwrap();
                         let res = left.binary_sub(right);
                         if let BinOpResult::Ok(res) = res {
-                            self.stack.push_unchecked(res);
+                            self.stack.push(res);
                         } else if let BinOpResult::Error(e) = res {
                             self.runtime_error(e);
                             return;
@@ -905,7 +1822,7 @@ impl VM {
                         let left = &*self.stack.pop().unwrap();
                         let res = left.binary_mul(right);
                         if let BinOpResult::Ok(res) = res {
-                            self.stack.push_unchecked(res);
+                            self.stack.push(res);
                         } else if let BinOpResult::Error(e) = res {
                             self.runtime_error(e);
                             return;
@@ -924,7 +1841,7 @@ impl VM {
                         let left = &*self.stack.pop().unwrap();
                         let res = left.binary_div(right);
                         if let BinOpResult::Ok(res) = res {
-                            self.stack.push_unchecked(res);
+                            self.stack.push(res);
                         } else if let BinOpResult::Error(e) = res {
                             self.runtime_error(e);
                             return;
@@ -943,7 +1860,7 @@ impl VM {
                         let left = &*self.stack.pop().unwrap();
                         let res = left.binary_mod(right);
                         if let BinOpResult::Ok(res) = res {
-                            self.stack.push_unchecked(res);
+                            self.stack.push(res);
                         } else if let BinOpResult::Error(e) = res {
                             self.runtime_error(e);
                             return;
@@ -962,7 +1879,7 @@ impl VM {
                         let left = &*self.stack.pop().unwrap();
                         let res = left.binary_exp(right);
                         if let BinOpResult::Ok(res) = res {
-                            self.stack.push_unchecked(res);
+                            self.stack.push(res);
                         } else if let BinOpResult::Error(e) = res {
                             self.runtime_error(e);
                             return;
@@ -980,14 +1897,14 @@ impl VM {
                         let right = &*self.stack.pop().unwrap();
                         let left = &*self.stack.pop().unwrap();
                         self.stack
-                            .push_unchecked(alloc_new_value(Value::Bool(left.is_equal(right))));
+                            .push(alloc_new_value(Value::Bool(left.is_equal(right))));
                     }
 
                     BINARY_NE => {
                         let right = &*self.stack.pop().unwrap();
                         let left = &*self.stack.pop().unwrap();
                         self.stack
-                            .push_unchecked(alloc_new_value(Value::Bool(!left.is_equal(right))));
+                            .push(alloc_new_value(Value::Bool(!left.is_equal(right))));
                     }
 
                     BINARY_LT => {
@@ -995,7 +1912,7 @@ impl VM {
                         let left = &*self.stack.pop().unwrap();
                         let res = left.binary_lt(right);
                         if let BinOpResult::Ok(res) = res {
-                            self.stack.push_unchecked(res);
+                            self.stack.push(res);
                         } else if let BinOpResult::Error(e) = res {
                             self.runtime_error(e);
                             return;
@@ -1014,7 +1931,7 @@ impl VM {
                         let left = &*self.stack.pop().unwrap();
                         let res = left.binary_le(right);
                         if let BinOpResult::Ok(res) = res {
-                            self.stack.push_unchecked(res);
+                            self.stack.push(res);
                         } else if let BinOpResult::Error(e) = res {
                             self.runtime_error(e);
                             return;
@@ -1033,7 +1950,7 @@ impl VM {
                         let left = &*self.stack.pop().unwrap();
                         let res = left.binary_gt(right);
                         if let BinOpResult::Ok(res) = res {
-                            self.stack.push_unchecked(res);
+                            self.stack.push(res);
                         } else if let BinOpResult::Error(e) = res {
                             self.runtime_error(e);
                             return;
@@ -1052,7 +1969,7 @@ impl VM {
                         let left = &*self.stack.pop().unwrap();
                         let res = left.binary_ge(right);
                         if let BinOpResult::Ok(res) = res {
-                            self.stack.push_unchecked(res);
+                            self.stack.push(res);
                         } else if let BinOpResult::Error(e) = res {
                             self.runtime_error(e);
                             return;
@@ -1066,6 +1983,83 @@ impl VM {
                         }
                     }
 
+                    // Superinstructions
+                    INCREMENT_LOCAL => {
+                        let index = self.base + self.read_bytecode() as usize;
+                        let constant = self.read_bytecode() as usize;
+                        // Overflows where the loads it replaces would have
+                        if self.stack.len() >= self.options.stack_size {
+                            self.runtime_error("Stack overflow".to_string());
+                            return;
+                        }
+                        let left = &*self.stack[index];
+                        let right = &self.constants[constant];
+                        match left.binary_add(right) {
+                            // The sum is a new value, so unlike REPLACE it is not copied
+                            BinOpResult::Ok(res) => {
+                                self.stack[index] = res;
+                                self.heap.shade_stored(res);
+                            }
+                            BinOpResult::Error(e) => {
+                                self.runtime_error(e);
+                                return;
+                            }
+                            BinOpResult::NoMatch => {
+                                let message = format!(
+                                    "Unsupported Binary operation: {} + {}",
+                                    left.type_name(),
+                                    right.type_name()
+                                );
+                                self.runtime_error(message);
+                                return;
+                            }
+                        }
+                    }
+
+                    COMPARE_LOCAL_JUMP => {
+                        let index = self.base + self.read_bytecode() as usize;
+                        let constant = self.read_bytecode() as usize;
+                        let comparison = self.read_bytecode();
+                        let address = self.read_bytecode();
+                        if self.stack.len() >= self.options.stack_size {
+                            self.runtime_error("Stack overflow".to_string());
+                            return;
+                        }
+                        match compare(&*self.stack[index], &self.constants[constant], comparison) {
+                            Ok(true) => (),
+                            Ok(false) => self.pc = address as usize,
+                            Err(e) => {
+                                self.runtime_error(e);
+                                return;
+                            }
+                        }
+                    }
+
+                    ADD_LOCALS => {
+                        let a = self.read_bytecode() as usize;
+                        let b = self.read_bytecode() as usize;
+                        if self.stack.len() >= self.options.stack_size {
+                            self.runtime_error("Stack overflow".to_string());
+                            return;
+                        }
+                        let left = &*self.stack[self.base + a];
+                        let right = &*self.stack[self.base + b];
+                        let res = left.binary_add(right);
+                        if let BinOpResult::Ok(res) = res {
+                            self.stack.push(res);
+                        } else if let BinOpResult::Error(e) = res {
+                            self.runtime_error(e);
+                            return;
+                        } else {
+                            self.runtime_error(format!(
+                                "Unsupported Binary operation: {} + {}",
+                                left.type_name(),
+                                right.type_name()
+                            ));
+                            return;
+                        }
+                    }
+
                     // Invalid
                     _ => {
                         self.runtime_error(format!("Unknown bytecode: {bc}"));
@@ -1073,22 +2067,138 @@ impl VM {
                     }
                 }
             }
+        }
+    }
 
-            iteration += 1;
+    // Runs a bounded amount of collection work, starting a cycle once enough was allocated.
+    // False once the heap outgrew max_heap, even after a full collection
+    fn gc_step(&mut self) -> bool {
+        if self.heap.phase() == GCPhase::Idle {
+            if self.heap.allocated_since_collect() >= self.options.gc_threshold {
+                self.gc_start_cycle();
+            }
+        } else {
+            self.gc_advance(self.options.gc_step_work);
         }
 
-        self.gc_recollect();
+        // Checked once the instruction that allocated has run, the error points at it
+        if let Some(max) = self.options.max_heap {
+            if self.heap.live() > max && self.pc != self.entry {
+                self.gc_recollect();
+                if self.heap.live() > max {
+                    self.runtime_error(format!("Out of memory: heap limit of {max} values"));
+                    return false;
+                }
+            }
+        }
+        true
     }
 
-    pub fn gc_recollect(&mut self) {
+    fn gc_start_cycle(&mut self) {
+        let start = Instant::now();
+        self.heap.start_marking();
+        self.gc_mark_roots();
+        self.gc_pause += start.elapsed();
+    }
+
+    fn gc_advance(&mut self, work: usize) {
+        let start = Instant::now();
+        match self.heap.phase() {
+            GCPhase::Idle => (),
+            GCPhase::Mark => {
+                // The stack has no write barrier, so it is scanned again before sweeping
+                if self.heap.mark_step(work) {
+                    self.gc_mark_roots();
+                    if self.heap.grey_count() == 0 {
+                        self.heap.start_sweeping();
+                    }
+                }
+            }
+            GCPhase::Sweep => {
+                if self.heap.sweep_step(work) && self.options.gc_verify {
+                    let mut roots = Vec::new();
+                    self.for_each_root(|p| roots.push(p));
+                    self.heap.verify(&roots);
+                }
+            }
+        }
+        self.gc_pause += start.elapsed();
+    }
+
+    pub fn gc_stats(&self) -> GCStats {
+        GCStats {
+            pause: self.gc_pause,
+            ..self.heap.stats()
+        }
+    }
+
+    // Everything still reachable starts from these: the stack, which also holds the globals
+    // and the locals of every frame, the last popped value, the values held by the host,
+    // and what constants point to, since constants themselves live outside the heap
+    fn for_each_root(&self, mut f: impl FnMut(*mut Value)) {
         for item in self.stack.iter() {
-            mark(*item);
+            f(*item);
         }
 
         if let Some(p) = self.last_popped {
-            mark(p);
+            f(p);
+        }
+
+        for p in self.host_roots.iter().flatten() {
+            f(*p);
+        }
+
+        for constant in self.constants.iter() {
+            for i in 0..constant.child_count() {
+                if let Some(child) = constant.child_at(i) {
+                    f(child);
+                }
+            }
+        }
+    }
+
+    fn gc_mark_roots(&mut self) {
+        let heap = self.heap.clone();
+        self.for_each_root(|p| heap.shade(p));
+    }
+
+    // Copies `value` and what it points to into the heap and keeps the copy alive until
+    // remove_root, so that the host can hold on to values across executions
+    pub fn add_root(&mut self, value: &Value) -> RootHandle {
+        let ptr = {
+            let _guard = self.heap.enter();
+            value.clone().deep_copy()
+        };
+        match self.host_roots.iter().position(|r| r.is_none()) {
+            Some(i) => {
+                self.host_roots[i] = Some(ptr);
+                RootHandle(i)
+            }
+            None => {
+                self.host_roots.push(Some(ptr));
+                RootHandle(self.host_roots.len() - 1)
+            }
         }
+    }
 
-        sweep();
+    pub fn get_root(&self, handle: RootHandle) -> *mut Value {
+        self.host_roots[handle.0].expect("Root handle was already removed")
+    }
+
+    pub fn remove_root(&mut self, handle: RootHandle) {
+        self.host_roots[handle.0] = None;
+    }
+
+    // Finishes the cycle in progress, then runs a whole one so that everything
+    // unreachable by now is freed
+    pub fn gc_recollect(&mut self) {
+        while self.heap.phase() != GCPhase::Idle {
+            self.gc_advance(usize::MAX);
+        }
+
+        self.gc_start_cycle();
+        while self.heap.phase() != GCPhase::Idle {
+            self.gc_advance(usize::MAX);
+        }
     }
 }